@@ -0,0 +1,168 @@
+use std::{fs::{File, OpenOptions}, io::Write, net::IpAddr, str::FromStr, sync::{Mutex, atomic::{AtomicBool, Ordering}}, time::SystemTime};
+
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use crate::{http::{Request, json_string}, metrics::Filter, util::{self, lock}};
+
+/// Set by the SIGUSR1 handler; the log is reopened before the next write.
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /increment HTTP/1.1" 200 0 counted`
+    Common,
+    /// Common, plus quoted referer and user-agent before the decision.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {s}")),
+        }
+    }
+}
+
+/// What became of a hit on `/increment`.
+#[derive(Clone, Copy)]
+pub enum Decision {
+    Counted,
+    Filtered(Filter),
+}
+
+impl Decision {
+    fn label(&self) -> &'static str {
+        match self {
+            Decision::Counted => "counted",
+            Decision::Filtered(Filter::Blacklist) => "filtered-ip",
+            Decision::Filtered(Filter::UserAgent) => "filtered-ua",
+            Decision::Filtered(Filter::Referer) => "filtered-referer",
+            Decision::Filtered(Filter::Unique) => "duplicate-unique",
+            Decision::Filtered(Filter::RateLimit) => "rate-limited",
+        }
+    }
+}
+
+/// One finished request, as it's written to the log.
+pub struct Entry<'a> {
+    pub ip:       Option<IpAddr>,
+    /// `None` if the request couldn't be parsed.
+    pub request:  Option<&'a Request>,
+    pub status:   Option<u16>,
+    pub bytes:    u64,
+    pub decision: Option<Decision>,
+}
+
+/// Access log file, one line per request. Reopened on SIGUSR1, so it can be
+/// rotated by moving it away and sending the signal.
+pub struct AccessLog {
+    path:   String,
+    format: LogFormat,
+    file:   Mutex<Option<File>>,
+}
+
+impl AccessLog {
+    pub fn new(path: String, format: LogFormat) -> Self {
+        let file = open(&path);
+        AccessLog { path, format, file: Mutex::new(file) }
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let line = match self.format {
+            LogFormat::Common => clf_line(entry, false),
+            LogFormat::Combined => clf_line(entry, true),
+            LogFormat::Json => json_line(entry),
+        };
+
+        let mut file = lock(&self.file);
+        if REOPEN.swap(false, Ordering::SeqCst) {
+            *file = open(&self.path);
+        }
+        if let Some(f) = file.as_mut() &&
+           let Err(e) = f.write_all(line.as_bytes()) {
+            error!("Error writing to access log {}: {e}", self.path);
+        }
+    }
+
+    /// Reopens the file if SIGUSR1 arrived since the last write, so the old
+    /// file is let go of even while there's no traffic.
+    pub fn reopen_if_requested(&self) {
+        if REOPEN.swap(false, Ordering::SeqCst) {
+            *lock(&self.file) = open(&self.path);
+        }
+    }
+}
+
+/// Makes SIGUSR1 request reopening the access log.
+pub fn handle_sigusr1() -> nix::Result<()> {
+    extern "C" fn on_sigusr1(_: nix::libc::c_int) {
+        REOPEN.store(true, Ordering::SeqCst);
+    }
+
+    let action = SigAction::new(SigHandler::Handler(on_sigusr1), SaFlags::SA_RESTART, SigSet::empty());
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe { signal::sigaction(Signal::SIGUSR1, &action) }.map(|_| ())
+}
+
+fn open(path: &str) -> Option<File> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            error!("Unable to open access log {path}: {e}");
+            None
+        },
+    }
+}
+
+fn clf_line(entry: &Entry, combined: bool) -> String {
+    let ip = entry.ip.map_or("-".to_owned(), |ip| ip.to_string());
+    let request = entry.request.map_or("-".to_owned(), |r| escape(&r.line));
+    let status = entry.status.map_or("-".to_owned(), |s| s.to_string());
+    let decision = entry.decision.map_or("-", |d| d.label());
+
+    let mut line = format!("{ip} - - [{}] \"{request}\" {status} {}", util::clf_date(SystemTime::now()), entry.bytes);
+    if combined {
+        let header = |name| entry.request.and_then(|r| r.header(name)).map_or("-".to_owned(), escape);
+        line.push_str(&format!(" \"{}\" \"{}\"", header("Referer"), header("User-Agent")));
+    }
+    line.push_str(&format!(" {decision}\n"));
+    line
+}
+
+fn json_line(entry: &Entry) -> String {
+    let string = |value: Option<&str>| value.map_or("null".to_owned(), json_string);
+    let request = entry.request;
+    let secs = SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    format!("{{\"time\":\"{}\",\"ip\":{},\"method\":{},\"path\":{},\"query\":{},\"status\":{},\"bytes\":{},\"referer\":{},\"user_agent\":{},\"decision\":{}}}\n",
+            util::iso_datetime(secs),
+            string(entry.ip.map(|ip| ip.to_string()).as_deref()),
+            string(request.map(|r| r.method.as_str())),
+            string(request.map(|r| r.path.as_str())),
+            string(request.and_then(|r| r.line.split_whitespace().nth(1)?.split_once('?').map(|(_, q)| q))),
+            entry.status.map_or("null".to_owned(), |s| s.to_string()),
+            entry.bytes,
+            string(request.and_then(|r| r.header("Referer"))),
+            string(request.and_then(|r| r.header("User-Agent"))),
+            string(entry.decision.map(|d| d.label())))
+}
+
+/// Escapes a value for a quoted log field the way nginx does: quotes,
+/// backslashes and non-printable bytes become `\xHH`.
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'"' | b'\\' | 0..0x20 | 0x7f.. => out.push_str(&format!("\\x{byte:02X}")),
+            byte => out.push(byte as char),
+        }
+    }
+    out
+}
@@ -0,0 +1,108 @@
+use crate::{Counter, DEFAULT_COUNTER, store, util::lock};
+use crate::http::{Connection, Request, json_string, respond, respond_with_headers, send_body};
+use crate::http::{OK, BAD_REQUEST, UNAUTHORIZED, NOT_FOUND, METHOD_NOT_ALLOWED};
+
+impl Counter {
+    /// Handles `/admin/*` requests. On the public listener they need the
+    /// `admin_token` and are hidden without one; on the admin listener the
+    /// token is only checked if it's set.
+    pub fn handle_admin(&self, stream: &mut Connection, request: &Request, admin_listener: bool) {
+        let authorized = match &self.config.admin_token {
+            Some(token) => request.header("Authorization")
+                                  .and_then(|auth| auth.strip_prefix("Bearer "))
+                                  .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes())),
+            None => admin_listener,
+        };
+        if !authorized {
+            if self.config.admin_token.is_none() {
+                send_status(stream, NOT_FOUND);
+                return;
+            }
+            warn!("Unauthorized admin request: {} {}", request.method, request.path);
+            if let Err(e) = respond_with_headers(stream, UNAUTHORIZED, &[("WWW-Authenticate", "Bearer".to_owned())]) {
+                warn!("Error sending response: {e}");
+            }
+            return;
+        }
+
+        let method = match request.path.as_str() {
+            "/admin/status" => "GET",
+            "/admin/set" | "/admin/reset" | "/admin/clear-uniques" => "POST",
+            _ => {
+                send_status(stream, NOT_FOUND);
+                return;
+            },
+        };
+        if request.method != method {
+            if let Err(e) = respond_with_headers(stream, METHOD_NOT_ALLOWED, &[("Allow", method.to_owned())]) {
+                warn!("Error sending response: {e}");
+            }
+            return;
+        }
+
+        let name = request.arg("id").unwrap_or(DEFAULT_COUNTER);
+        let body = match request.path.as_str() {
+            "/admin/status" => Some((self.status_json(), None)),
+            "/admin/set" => {
+                let Some(value) = request.arg("value").and_then(|v| v.parse::<usize>().ok()) else {
+                    info!("Admin set without a valid value");
+                    send_status(stream, BAD_REQUEST);
+                    return;
+                };
+                self.with_tally(name, false, |tally| {
+                    let snapshot = tally.set(value);
+                    info!("Counter {name} set to {value} by admin");
+                    (format!("{{\"name\":{},\"count\":{}}}", json_string(name), tally.count), snapshot)
+                })
+            },
+            "/admin/reset" => self.with_tally(name, false, |tally| {
+                let snapshot = tally.set(0);
+                info!("Counter {name} reset by admin");
+                (format!("{{\"name\":{},\"count\":{}}}", json_string(name), tally.count), snapshot)
+            }),
+            _ => self.with_tally(name, false, |tally| {
+                let snapshot = tally.clear_uniques();
+                info!("Uniques of counter {name} cleared by admin");
+                (format!("{{\"name\":{},\"uniques\":{}}}", json_string(name), tally.uniques.len()), snapshot)
+            }),
+        };
+
+        match body {
+            Some((body, snapshot)) => {
+                // Written before answering, so a change is on disk once it's confirmed
+                store::write_snapshots(snapshot);
+                send_body(stream, OK, "application/json", body.as_bytes(), &[("Cache-Control", "no-store".to_owned())]);
+            },
+            None => {
+                info!("Unknown counter: {name}");
+                send_status(stream, NOT_FOUND);
+            },
+        }
+    }
+
+    fn status_json(&self) -> String {
+        let tallies = lock(&self.tallies);
+        let mut names: Vec<&String> = tallies.keys().collect();
+        names.sort();
+
+        let counters: Vec<String> = names.into_iter().map(|name| {
+            let tally = &tallies[name];
+            format!("{{\"name\":{},\"count\":{},\"unsaved\":{},\"uniques\":{}}}",
+                    json_string(name), tally.count, tally.unsaved, tally.uniques.len())
+        }).collect();
+
+        format!("{{\"counters\":[{}]}}", counters.join(","))
+    }
+}
+
+fn send_status(stream: &mut Connection, code: &str) {
+    if let Err(e) = respond(stream, code, None) {
+        warn!("Error sending response: {e}");
+    }
+}
+
+/// Compares secrets without bailing out at the first differing byte, so
+/// response timing doesn't reveal how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
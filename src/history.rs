@@ -0,0 +1,192 @@
+use std::{collections::BTreeMap, io::ErrorKind, time::{SystemTime, UNIX_EPOCH}};
+
+const MAGIC: &[u8; 4] = b"NBH1";
+const HOUR: u64 = 3600;
+const DAY: u64 = 86400;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Granularity {
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    pub fn secs(&self) -> u64 {
+        match self {
+            Granularity::Hour => HOUR,
+            Granularity::Day => DAY,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+}
+
+/// Visits per UTC hour and per UTC day. Buckets are keyed by their number
+/// since the Unix epoch, and ones older than the retention are dropped.
+pub struct History {
+    hourly: BTreeMap<u32, u32>,
+    daily:  BTreeMap<u32, u32>,
+    /// How many hourly and daily buckets to keep, including the current one.
+    hours:  u32,
+    days:   u32,
+}
+
+impl History {
+    pub fn new(hours: u32, days: u32) -> Self {
+        History { hourly: BTreeMap::new(), daily: BTreeMap::new(), hours: hours.max(1), days: days.max(1) }
+    }
+
+    /// Loads a history saved by `save`. A missing file gives an empty history;
+    /// a damaged one is reported and replaced, since the total in the counter
+    /// file doesn't depend on it.
+    pub fn load(path: &str, hours: u32, days: u32) -> Self {
+        let mut history = History::new(hours, days);
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return history,
+            Err(e) => {
+                warn!("Unable to read history from {path}: {e}; starting with an empty one");
+                return history;
+            },
+        };
+
+        match decode(&bytes) {
+            Some((hourly, daily)) => {
+                history.hourly = hourly;
+                history.daily = daily;
+                history.prune(now());
+            },
+            None => warn!("History file {path} is corrupt; starting with an empty one"),
+        }
+        history
+    }
+
+    /// Encodes the history for `load` as `NBH1`, then for hourly and daily 
+    /// buckets in turn a little-endian `u32` bucket count followed by 
+    /// `(bucket, visits)` pairs of little-endian `u32`s: 8 bytes per bucket.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12 + 8 * (self.hourly.len() + self.daily.len()));
+        bytes.extend_from_slice(MAGIC);
+        for buckets in [&self.hourly, &self.daily] {
+            bytes.extend_from_slice(&(buckets.len() as u32).to_le_bytes());
+            for (bucket, visits) in buckets {
+                bytes.extend_from_slice(&bucket.to_le_bytes());
+                bytes.extend_from_slice(&visits.to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    /// Counts a visit in the current hour and day.
+    pub fn record(&mut self) {
+        let now = now();
+        for (buckets, granularity) in [(&mut self.hourly, HOUR), (&mut self.daily, DAY)] {
+            let visits = buckets.entry((now / granularity) as u32).or_insert(0);
+            *visits = visits.saturating_add(1);
+        }
+        self.prune(now);
+    }
+
+    /// Visits per bucket from the bucket containing `from` up to the one
+    /// containing `to`, as (bucket start, visits) pairs. Empty buckets are
+    /// included; the range is cut to what's retained.
+    pub fn series(&self, granularity: Granularity, from: u64, to: u64) -> Vec<(u64, u32)> {
+        let (buckets, retention) = match granularity {
+            Granularity::Hour => (&self.hourly, self.hours),
+            Granularity::Day => (&self.daily, self.days),
+        };
+        let secs = granularity.secs();
+        let current = now() / secs;
+        let oldest = (current + 1).saturating_sub(retention as u64);
+
+        let first = (from / secs).max(oldest);
+        let last = (to / secs).min(current);
+        (first..=last).map(|bucket| (bucket * secs, buckets.get(&(bucket as u32)).copied().unwrap_or(0)))
+                      .collect()
+    }
+
+    fn prune(&mut self, now: u64) {
+        let oldest_hour = ((now / HOUR) + 1).saturating_sub(self.hours as u64) as u32;
+        let oldest_day = ((now / DAY) + 1).saturating_sub(self.days as u64) as u32;
+        self.hourly = self.hourly.split_off(&oldest_hour);
+        self.daily = self.daily.split_off(&oldest_day);
+    }
+}
+
+fn decode(bytes: &[u8]) -> Option<(BTreeMap<u32, u32>, BTreeMap<u32, u32>)> {
+    let mut rest = bytes.strip_prefix(MAGIC)?;
+    let mut next = || -> Option<u32> {
+        let (word, tail) = rest.split_first_chunk::<4>()?;
+        rest = tail;
+        Some(u32::from_le_bytes(*word))
+    };
+
+    let mut sections = [BTreeMap::new(), BTreeMap::new()];
+    for buckets in sections.iter_mut() {
+        for _ in 0..next()? {
+            buckets.insert(next()?, next()?);
+        }
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+
+    let [hourly, daily] = sections;
+    Some((hourly, daily))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_round_trips() {
+        let mut history = History::new(24, 30);
+        history.hourly.extend([(10, 1), (12, 300), (u32::MAX, u32::MAX)]);
+        history.daily.extend([(5, 7)]);
+
+        let bytes = history.encode();
+        assert_eq!(bytes.len(), 12 + 8 * 4);
+        assert_eq!(decode(&bytes), Some((history.hourly, history.daily)));
+
+        assert_eq!(decode(&History::new(1, 1).encode()), Some((BTreeMap::new(), BTreeMap::new())));
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let mut history = History::new(24, 30);
+        history.hourly.insert(10, 1);
+        let bytes = history.encode();
+
+        assert_eq!(decode(b""), None);
+        assert_eq!(decode(b"NBH0\0\0\0\0\0\0\0\0"), None);
+        assert_eq!(decode(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(decode(&[bytes.as_slice(), &[0]].concat()), None);
+    }
+
+    #[test]
+    fn records_and_fills_in_empty_buckets() {
+        let mut history = History::new(3, 2);
+        history.record();
+        history.record();
+
+        let now = now();
+        let hours = history.series(Granularity::Hour, 0, now);
+        assert_eq!(hours.len(), 3);
+        assert_eq!(hours.iter().map(|(_, visits)| visits).collect::<Vec<_>>(), [&0, &0, &2]);
+        assert_eq!(hours[2].0, now / HOUR * HOUR);
+
+        let days = history.series(Granularity::Day, now, now);
+        assert_eq!(days, [(now / DAY * DAY, 2)]);
+    }
+}
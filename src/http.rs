@@ -0,0 +1,429 @@
+use std::{collections::HashMap, io::{BufRead, BufWriter, ErrorKind, Read, Write}, net::TcpStream, time::Instant};
+
+pub const OK: &str = "200 OK";
+pub const NOT_MODIFIED: &str = "304 Not Modified";
+pub const BAD_REQUEST: &str = "400 Bad Request";
+pub const UNAUTHORIZED: &str = "401 Unauthorized";
+// pub const FORBIDDEN: &str = "403 Forbidden";
+pub const NOT_FOUND: &str = "404 Not Found";
+pub const METHOD_NOT_ALLOWED: &str = "405 Method Not Allowed";
+// pub const TEAPOT: &str = "418 I'm a teapot";
+pub const TOO_MANY_REQUESTS: &str = "429 Too Many Requests";
+pub const HEADERS_TOO_LARGE: &str = "431 Request Header Fields Too Large";
+pub const INTERNAL_ERROR: &str = "500 Internal Server Error";
+pub const SERVICE_UNAVAILABLE: &str = "503 Service Unavailable";
+
+const MAX_REQUEST_LINE: usize = 8192;
+const MAX_HEADERS_SIZE: usize = 16384;
+const MAX_HEADERS:      usize = 64;
+
+/// A parsed HTTP/1.x request head. Header names are stored lowercased;
+/// repeated headers are joined with `, `.
+pub struct Request {
+    /// The request line as received, e.g. `GET /get?n=1 HTTP/1.1`.
+    pub line:    String,
+    pub method:  String,
+    pub path:    String,
+    pub query:   HashMap<String, String>,
+    headers:     HashMap<String, String>,
+}
+
+impl Request {
+    /// Looks a header up by its case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(|v| v.as_str())
+    }
+
+    /// Looks a query argument up, returning `None` if it's missing.
+    pub fn arg(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(|v| v.as_str())
+    }
+}
+
+/// A client connection that remembers the status code and size of the
+/// response written to it.
+pub struct Connection {
+    stream:  TcpStream,
+    status:  Option<u16>,
+    written: u64,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Self {
+        Connection { stream, status: None, written: 0 }
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Status code of the response, if one was sent.
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    /// Bytes written, headers included.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.status.is_none() {
+            // `HTTP/1.1 200 OK`; responses start with a whole status line
+            self.status = buf.get(9..12).and_then(|code| std::str::from_utf8(code).ok()?.parse().ok());
+        }
+        let written = self.stream.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Reads from a connection until a deadline. The socket's read timeout is
+/// shortened as the deadline nears, so a client trickling in a byte at a 
+/// time can't hold a worker for longer than the deadline allows.
+pub struct DeadlineReader<'a> {
+    stream:   &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> DeadlineReader<'a> {
+    pub fn new(stream: &'a TcpStream, deadline: Instant) -> Self {
+        DeadlineReader { stream, deadline }
+    }
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::Error::new(ErrorKind::TimedOut, "request not received in time"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+pub enum ParseError {
+    /// Connection failed or was closed before the request head ended.
+    Io(std::io::Error),
+    /// The request doesn't look like HTTP; answer with 400.
+    Malformed(&'static str),
+    /// Header lines exceed `MAX_HEADERS_SIZE` or `MAX_HEADERS`; answer with 431.
+    TooLarge(&'static str),
+}
+
+/// Reads a request line and headers, stopping at the empty line. Lines may
+/// end with either `\r\n` or `\n`.
+pub fn read_request(reader: &mut impl BufRead) -> Result<Request, ParseError> {
+    let request_line = match read_line(reader, MAX_REQUEST_LINE)? {
+        Some(line) => line,
+        None => return Err(ParseError::Malformed("request line too long")),
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(ParseError::Malformed("malformed request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(ParseError::Malformed("unsupported HTTP version"));
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, parse_query(query)),
+        None => (target, HashMap::new()),
+    };
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut last_header: Option<String> = None;
+    let mut size = 0;
+    let mut count = 0;
+    loop {
+        let line = match read_line(reader, MAX_HEADERS_SIZE.saturating_sub(size))? {
+            Some(line) => line,
+            None => return Err(ParseError::TooLarge("request headers too large")),
+        };
+        if line.is_empty() {
+            break;
+        }
+        size += line.len() + 2;
+
+        // Obsolete line folding: continues the previous header's value
+        if line.starts_with([' ', '\t']) {
+            let Some(value) = last_header.as_ref().and_then(|name| headers.get_mut(name)) else {
+                return Err(ParseError::Malformed("folded line without a header"));
+            };
+            value.push(' ');
+            value.push_str(line.trim());
+            continue;
+        }
+
+        count += 1;
+        if count > MAX_HEADERS {
+            return Err(ParseError::TooLarge("too many request headers"));
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ParseError::Malformed("header line without a colon"));
+        };
+        if name.is_empty() || name.ends_with([' ', '\t']) {
+            return Err(ParseError::Malformed("malformed header name"));
+        }
+
+        let name = name.to_ascii_lowercase();
+        let value = value.trim();
+        headers.entry(name.clone())
+               .and_modify(|v| { v.push_str(", "); v.push_str(value); })
+               .or_insert_with(|| value.to_owned());
+        last_header = Some(name);
+    }
+
+    Ok(Request {
+        line: request_line.clone(),
+        method: method.to_owned(),
+        path: percent_decode(path, false),
+        query,
+        headers,
+    })
+}
+
+/// Reads one line without its line ending. Returns `None` if the line is
+/// longer than `limit` bytes.
+fn read_line(reader: &mut impl BufRead, limit: usize) -> Result<Option<String>, ParseError> {
+    let mut buf = Vec::new();
+    let read = reader.take(limit as u64 + 1)
+                     .read_until(b'\n', &mut buf)
+                     .map_err(ParseError::Io)?;
+
+    if read == 0 {
+        return Err(ParseError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    if !buf.ends_with(b"\n") {
+        if buf.len() > limit {
+            return Ok(None);
+        }
+        return Err(ParseError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    buf.pop();
+    if buf.ends_with(b"\r") {
+        buf.pop();
+    }
+
+    Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+/// Parses `a=1&b=2` into a map; arguments without `=` get an empty value and
+/// later duplicates override earlier ones.
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query.split('&')
+         .filter(|arg| !arg.is_empty())
+         .map(|arg| match arg.split_once('=') {
+             Some((name, value)) => (percent_decode(name, true), percent_decode(value, true)),
+             None => (percent_decode(arg, true), String::new()),
+         })
+         .collect()
+}
+
+/// Decodes `%XX` escapes (and `+` as a space in query strings). Invalid
+/// escapes are left as they are.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                // `from_str_radix` alone would also take a sign, as in `%+1`
+                let digits = &bytes[i + 1..i + 3];
+                let hex = std::str::from_utf8(digits).ok()
+                                                     .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                                                     .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 3;
+                        continue;
+                    },
+                    None => out.push(b'%'),
+                }
+            },
+            b'+' if plus_as_space => out.push(b' '),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub fn respond(stream: &mut impl Write, code: &str, content_type: Option<&str>) -> std::io::Result<()> {
+    stream.write_all(format!("HTTP/1.1 {code}\r\n").as_bytes())?;
+    if let Some(ctype) = content_type {
+        stream.write_all(format!("Content-Type: {ctype}\r\n").as_bytes())?;
+    }
+    stream.write_all("\r\n".as_bytes())?;
+
+    Ok(())
+}
+
+pub fn respond_with_headers(stream: &mut impl Write, code: &str, headers: &[(&str, String)]) -> std::io::Result<()> {
+    let mut response = format!("HTTP/1.1 {code}\r\n");
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+
+    stream.write_all(response.as_bytes())
+}
+
+pub fn send_body(stream: &mut impl Write, code: &str, content_type: &str, body: &[u8], extra_headers: &[(&str, String)]) {
+    let mut stream = BufWriter::new(stream);
+    let mut headers = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n", code, content_type, body.len());
+    for (name, value) in extra_headers {
+        headers.push_str(&format!("{name}: {value}\r\n"));
+    }
+    headers.push_str("\r\n");
+
+    if let Err(e) = stream.write_all(headers.as_bytes()) {
+        warn!("Error writing bytes to stream buffer! {e}");
+    } else if let Err(e) = stream.write_all(body) {
+        warn!("Error writing file to stream buffer! {e}");
+    }
+
+    if let Err(e) = stream.flush() {
+        warn!("Error flushing stream buffer! {e}");
+    }
+}
+
+/// Extracts the lowercased host from an absolute URL such as a `Referer` or
+/// `Origin` value, without userinfo or port.
+pub fn url_host(url: &str) -> Option<String> {
+    let (_scheme, rest) = url.trim().split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit_once('@').map(|(_userinfo, host)| host).unwrap_or(authority);
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split_once(']')?.0,
+        None => host.split_once(':').map(|(host, _port)| host).unwrap_or(host),
+    };
+
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
+/// Quotes a string for use in a JSON document.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Checks an `If-None-Match` header value against an entity tag.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',')
+                 .map(|tag| tag.trim().trim_start_matches("W/"))
+                 .any(|tag| tag == "*" || tag == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        read_request(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let Ok(request) = parse("GET /get?n=1&id=my%20blog HTTP/1.1\r\nHost: example.com\r\nuser-agent:  test \r\n\r\n") else {
+            panic!("request not parsed");
+        };
+        assert_eq!(request.line, "GET /get?n=1&id=my%20blog HTTP/1.1");
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/get");
+        assert_eq!(request.arg("n"), Some("1"));
+        assert_eq!(request.arg("id"), Some("my blog"));
+        assert_eq!(request.header("User-Agent"), Some("test"));
+        assert_eq!(request.header("HOST"), Some("example.com"));
+    }
+
+    #[test]
+    fn accepts_bare_newlines_and_joins_repeated_headers() {
+        let Ok(request) = parse("GET / HTTP/1.0\nAccept: a\nAccept: b\n\n") else {
+            panic!("request not parsed");
+        };
+        assert_eq!(request.header("Accept"), Some("a, b"));
+    }
+
+    #[test]
+    fn unfolds_continuation_lines() {
+        let Ok(request) = parse("GET / HTTP/1.1\r\nX-Long: first\r\n \t second\r\n\r\n") else {
+            panic!("request not parsed");
+        };
+        assert_eq!(request.header("X-Long"), Some("first second"));
+
+        assert!(matches!(parse("GET / HTTP/1.1\r\n folded\r\n\r\n"), Err(ParseError::Malformed(_))));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(matches!(parse("GET /\r\n\r\n"), Err(ParseError::Malformed(_))));
+        assert!(matches!(parse("GET / HTTP/2\r\n\r\n"), Err(ParseError::Malformed(_))));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nNo colon\r\n\r\n"), Err(ParseError::Malformed(_))));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost : x\r\n\r\n"), Err(ParseError::Malformed(_))));
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_REQUEST_LINE));
+        assert!(matches!(parse(&long_line), Err(ParseError::Malformed(_))));
+
+        let big_header = format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "a".repeat(MAX_HEADERS_SIZE));
+        assert!(matches!(parse(&big_header), Err(ParseError::TooLarge(_))));
+
+        let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(MAX_HEADERS + 1));
+        assert!(matches!(parse(&many_headers), Err(ParseError::TooLarge(_))));
+
+        let enough_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(MAX_HEADERS));
+        assert!(parse(&enough_headers).is_ok());
+    }
+
+    #[test]
+    fn reports_truncated_requests_as_io_errors() {
+        assert!(matches!(parse(""), Err(ParseError::Io(_))));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: x\r\n"), Err(ParseError::Io(_))));
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc", false), "a b/c");
+        assert_eq!(percent_decode("a+b", true), "a b");
+        assert_eq!(percent_decode("a+b", false), "a+b");
+        assert_eq!(percent_decode("%e2%9c%93", false), "✓");
+    }
+
+    #[test]
+    fn leaves_invalid_escapes_alone() {
+        assert_eq!(percent_decode("%+1", false), "%+1");
+        assert_eq!(percent_decode("%-1x", false), "%-1x");
+        assert_eq!(percent_decode("%zz", false), "%zz");
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%4", false), "%4");
+    }
+}
@@ -0,0 +1,105 @@
+use std::{hash::{DefaultHasher, Hash, Hasher}, time::SystemTime};
+
+/// A digit set compiled into the binary.
+pub struct Theme {
+    pub name:         &'static str,
+    pub content_type: &'static str,
+    /// Images for digits 0 to 9 followed by the `empty` image.
+    pub images:       [&'static [u8]; 11],
+}
+
+macro_rules! theme {
+    ($name: expr, $dir: expr, $ext: expr, $content_type: expr) => {
+        Theme {
+            name: $name,
+            content_type: $content_type,
+            images: [
+                include_bytes!(concat!("../", $dir, "/0.", $ext)),
+                include_bytes!(concat!("../", $dir, "/1.", $ext)),
+                include_bytes!(concat!("../", $dir, "/2.", $ext)),
+                include_bytes!(concat!("../", $dir, "/3.", $ext)),
+                include_bytes!(concat!("../", $dir, "/4.", $ext)),
+                include_bytes!(concat!("../", $dir, "/5.", $ext)),
+                include_bytes!(concat!("../", $dir, "/6.", $ext)),
+                include_bytes!(concat!("../", $dir, "/7.", $ext)),
+                include_bytes!(concat!("../", $dir, "/8.", $ext)),
+                include_bytes!(concat!("../", $dir, "/9.", $ext)),
+                include_bytes!(concat!("../", $dir, "/empty.", $ext)),
+            ],
+        }
+    };
+}
+
+pub const DEFAULT_THEME: &str = "default";
+
+pub const THEMES: &[Theme] = &[
+    theme!("default", "img", "jpg", "image/jpeg"),
+    theme!("sweeper", "img/sweeper", "png", "image/png"),
+];
+
+pub fn theme(name: &str) -> Option<&'static Theme> {
+    THEMES.iter().find(|t| t.name == name)
+}
+
+const DIGIT_NAMES: [&str; 11] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "empty"];
+
+/// Digit images held in memory, together with what's needed to answer 
+/// conditional requests for them.
+pub struct DigitSet {
+    images:           Vec<Vec<u8>>,
+    etags:            Vec<String>,
+    pub content_type: String,
+    pub modified:     SystemTime,
+}
+
+impl DigitSet {
+    pub fn from_theme(theme: &Theme) -> Self {
+        let images = theme.images.iter().map(|i| i.to_vec()).collect::<Vec<_>>();
+        DigitSet {
+            etags: images.iter().map(|i| etag(i)).collect(),
+            images,
+            content_type: theme.content_type.to_owned(),
+            modified: SystemTime::now(),
+        }
+    }
+
+    pub fn from_dir(dir: &str, format: &str, content_type: &str) -> std::io::Result<Self> {
+        let modified = dir_modified(dir, format)?;
+        let mut images = Vec::new();
+        for name in DIGIT_NAMES {
+            images.push(std::fs::read(format!("{dir}/{name}.{format}"))?);
+        }
+
+        Ok(DigitSet {
+            etags: images.iter().map(|i| etag(i)).collect(),
+            images,
+            content_type: content_type.to_owned(),
+            modified,
+        })
+    }
+
+    pub fn image(&self, digit: Option<u8>) -> &[u8] {
+        &self.images[digit.map(usize::from).unwrap_or(10)]
+    }
+
+    pub fn etag(&self, digit: Option<u8>) -> &str {
+        &self.etags[digit.map(usize::from).unwrap_or(10)]
+    }
+}
+
+/// Latest modification time of an image directory and the digit files in it.
+pub fn dir_modified(dir: &str, format: &str) -> std::io::Result<SystemTime> {
+    let mut latest = std::fs::metadata(dir)?.modified()?;
+    for name in DIGIT_NAMES {
+        let modified = std::fs::metadata(format!("{dir}/{name}.{format}"))?.modified()?;
+        latest = latest.max(modified);
+    }
+
+    Ok(latest)
+}
+
+pub fn etag(body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
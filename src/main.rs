@@ -1,72 +1,191 @@
-use std::{collections::HashMap, fs::File, io::{BufRead, BufReader, BufWriter, Read, Write}, net::{IpAddr, TcpListener, TcpStream}, process::exit, str::from_utf8, time::Instant};
-use regex::Regex;
+use std::{collections::HashMap, io::BufReader, net::{IpAddr, TcpListener, TcpStream}, process::exit, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use http::{Connection, DeadlineReader, ParseError, Request, etag_matches, json_string, respond, respond_with_headers, send_body, url_host};
+use http::{OK, NOT_MODIFIED, BAD_REQUEST, NOT_FOUND, METHOD_NOT_ALLOWED, TOO_MANY_REQUESTS, HEADERS_TOO_LARGE, INTERNAL_ERROR, SERVICE_UNAVAILABLE};
+use regex::{Regex, RegexSet};
+use accesslog::{AccessLog, Decision, Entry, LogFormat};
+use history::{Granularity, History};
+use images::{DigitSet, Theme};
+use metrics::{Filter, Metrics, TallySample};
+use render::{Renderer, SvgStyle};
 use toml::Table;
-use util::{kill_old_counter, remove_pid_file};
+use net::{ForwardedHeader, IpNet, PrefixSet};
+use pool::Pool;
+use ratelimit::{Limit, RateLimiter};
+use store::{Slot, Snapshot};
+use uniques::Uniques;
+use util::{Level, kill_old_counter, lock, remove_pid_file};
 
 #[macro_use] mod util;
+mod history;
+mod http;
+mod accesslog;
+mod admin;
+mod images;
+mod metrics;
+mod net;
+mod pool;
+mod ratelimit;
+mod render;
 mod single;
+mod store;
+mod uniques;
 
 const COUNTER_FILE: &str = "count.bin";
+const COUNTERS_DIR: &str = "counters";
+const DEFAULT_COUNTER: &str = "default";
+const MAX_NAME_LEN: usize = 64;
 
 const BIND_ADDR:    &str = "0.0.0.0:1234";
-const IMAGE_DIR:    &str = "img";
 const IMG_FORMAT:   &str = "jpg";
 const CONTENT_TYPE: &str = "image/jpeg";
 const TIMEOUT:      u64  = 3600;
+const SALT_ROTATION: u64 = 86400;
+const MAX_UNIQUES:  usize = 100_000;
+const MAX_COUNTERS: usize = 1000;
+const TRUSTED_PROXIES: &[&str] = &["127.0.0.0/8", "::1"];
+const DIGITS:       usize = 5;
+const MAX_DIGITS:   usize = 20;
+const CACHE_CONTROL: &str = "no-cache";
+const FLUSH_EVERY:  usize = 1;
+const WORKERS:      usize = 4;
+const MAX_CONNECTIONS: usize = 64;
+const CONNECTION_TIMEOUT: u64 = 10;
+const IMAGES_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+const LISTS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_ADMIN_TOKEN_LEN: usize = 16;
+const HISTORY_HOURS: u32 = 24 * 7;
+const HISTORY_DAYS: u32 = 365;
+const MAX_HISTORY_BUCKETS: i64 = 10_000;
 
 struct Config {
     counterfile:    String,
+    counters_dir:   String,
+    flush_every:    usize,
+    flush_interval: Option<Duration>,
+    counters:       Vec<String>,
+    create_counters: bool,
+    max_counters:   usize,
     bind_addr:      String,
-    image_dir:      String,
+    workers:        usize,
+    max_connections: usize,
+    read_timeout:   Duration,
+    write_timeout:  Duration,
+    image_dir:      Option<String>,
+    theme:          &'static Theme,
     img_format:     String,
     content_type:   String,
+    cache_control:  String,
+    digits:         usize,
+    renderer:       Renderer,
+    svg_style:      SvgStyle,
     count_unique:   bool,
     timeout:        u64,
-    blacklist:      Vec<IpAddr>,
-    ua_list:        Vec<Regex>,
-    allow_empty_ua: bool
+    hash_rotation:  Option<Duration>,
+    max_uniques:    usize,
+    blacklist:      PrefixSet,
+    ipv6_unique_prefix: u8,
+    trusted_proxies: PrefixSet,
+    client_ip_header: ForwardedHeader,
+    ua_list:        RegexSet,
+    ua_allowlist:   RegexSet,
+    ip_allowlist:   Option<PrefixSet>,
+    allow_empty_ua: bool,
+    allowed_referers: Vec<String>,
+    allow_empty_referer: bool,
+    increment_limit: Option<Limit>,
+    image_limit:    Option<Limit>,
+    admin_token:    Option<String>,
+    admin_bind_addr: Option<String>,
+    cors_origins:   Vec<String>,
+    history:        bool,
+    history_hours:  u32,
+    history_days:   u32,
+    metrics:        bool,
+    metrics_bind_addr: Option<String>,
+    access_log:     Option<String>,
+    access_log_format: LogFormat,
+    log_level:      Option<Level>
 } 
 
 impl Default for Config {
     fn default() -> Self {
         Config { 
             counterfile: COUNTER_FILE.to_owned(), 
+            counters_dir: COUNTERS_DIR.to_owned(),
+            flush_every: FLUSH_EVERY,
+            flush_interval: None,
+            counters: Vec::new(),
+            create_counters: false,
+            max_counters: MAX_COUNTERS,
             bind_addr: BIND_ADDR.to_owned(), 
-            image_dir: IMAGE_DIR.to_owned(), 
+            workers: WORKERS,
+            max_connections: MAX_CONNECTIONS,
+            read_timeout: Duration::from_secs(CONNECTION_TIMEOUT),
+            write_timeout: Duration::from_secs(CONNECTION_TIMEOUT),
+            image_dir: None,
+            theme: images::theme(images::DEFAULT_THEME).unwrap(),
             img_format: IMG_FORMAT.to_owned(), 
             content_type: CONTENT_TYPE.to_owned(),
+            cache_control: CACHE_CONTROL.to_owned(),
+            digits: DIGITS,
+            renderer: Renderer::Images,
+            svg_style: SvgStyle::default(),
             count_unique: false,
             timeout: TIMEOUT,
-            blacklist: Vec::new(),
-            ua_list: Vec::new(),
-            allow_empty_ua: false
+            hash_rotation: None,
+            max_uniques: MAX_UNIQUES,
+            blacklist: PrefixSet::default(),
+            ipv6_unique_prefix: 128,
+            trusted_proxies: TRUSTED_PROXIES.iter().filter_map(|net| net.parse().ok()).collect(),
+            client_ip_header: ForwardedHeader::XRealIp,
+            ua_list: RegexSet::empty(),
+            ua_allowlist: RegexSet::empty(),
+            ip_allowlist: None,
+            allow_empty_ua: false,
+            allowed_referers: Vec::new(),
+            allow_empty_referer: true,
+            increment_limit: None,
+            image_limit: None,
+            admin_token: None,
+            admin_bind_addr: None,
+            cors_origins: Vec::new(),
+            history: false,
+            history_hours: HISTORY_HOURS,
+            history_days: HISTORY_DAYS,
+            metrics: false,
+            metrics_bind_addr: None,
+            access_log: None,
+            access_log_format: LogFormat::Combined,
+            log_level: None
         }
     }
 }
 
-const OK: &str = "200 OK";
-const BAD_REQUEST: &str = "400 Bad Request";
-// const FORBIDDEN: &str = "403 Forbidden";
-// const TEAPOT: &str = "418 I'm a teapot";
-const INTERNAL_ERROR: &str = "500 Internal Server Error";
+/// Built-in crawler signatures for `filter_known_bots`.
+const KNOWN_BOTS: &str = include_str!("bots.txt");
 
 const INSTANCE_UUID: &str = "1e5319b4-73ca-447d-a05d-eca92225ebb9";
 
 fn main() {
-    let single = single::SingleInstance::new(INSTANCE_UUID);
-    let mut single = single.ok();
+    let log_level_set = set_log_level_from_args_or_env();
+
+    let mut single = match single::SingleInstance::new(INSTANCE_UUID) {
+        Ok(single) => Some(single),
+        Err(e) => {
+            warn!("Unable to check for another running instance: {e}");
+            None
+        },
+    };
     
     if single.as_ref().is_some_and(|inst| !inst.is_single()) {
         loop {
             eprint!("Another counter instance is running. Do you want to stop that old instance? (y/N) > ");
 
             let mut choice = String::new();
-            match std::io::stdin().read_line(&mut choice) {
-                Err(e) => {
-                    eprintln!("Error reading user input: {e}. Exiting...");
-                    exit(1);
-                }
-                Ok(_) => {},
+            if let Err(e) = std::io::stdin().read_line(&mut choice) {
+                eprintln!("Error reading user input: {e}. Exiting...");
+                exit(1);
             }
 
             match choice.trim() {
@@ -80,7 +199,7 @@ fn main() {
                         retries -= 1;
                     }
                     if single.as_ref().is_none_or(|inst| !inst.is_single()) {
-                        debugprint!("Wasn't able to reaquire a socket. Proceeding anyway...");
+                        warn!("Wasn't able to reaquire a socket. Proceeding anyway...");
                     }
                     break;
                 },
@@ -93,44 +212,90 @@ fn main() {
     if single.as_ref().is_some_and(|inst| inst.is_single()) {
         let pid = nix::unistd::getpid().as_raw().to_string();
         if let Err(e) = std::fs::write(".counter.pid", pid.clone()) {
-            debugprint!("Unable to write PID in current directory. Trying temp directory... ", e);
+            debug!("Unable to write PID in current directory. Trying temp directory... {e}");
             
             let mut tmp = std::env::temp_dir();
             tmp.set_file_name(".counter.pid");
             if let Err(e) = std::fs::write(tmp, pid) {
-                debugprint!("Unable to write PID to a file. Another instance won't be able to kill this one.", e);
+                warn!("Unable to write PID to a file. Another instance won't be able to kill this one. {e}");
             }
         }
     }
 
-    if let Err(e) = ctrlc::set_handler(|| {
+    let mut config: Config = Config::default();
+    load_config_from_file(&mut config, "config.toml");
+    if !log_level_set && let Some(level) = config.log_level {
+        util::set_log_level(level);
+    }
+
+    info!("Listening on {}", config.bind_addr);
+
+    let bind_addr = config.bind_addr.clone();
+    let admin_bind_addr = config.admin_bind_addr.clone();
+    let metrics_bind_addr = config.metrics_bind_addr.clone();
+    let access_log = config.access_log.is_some();
+    let (workers, max_connections) = (config.workers, config.max_connections);
+    let (read_timeout, write_timeout) = (config.read_timeout, config.write_timeout);
+    let flush_interval = config.flush_interval;
+    let counter = match Counter::new(config) {
+        Ok(counter) => Arc::new(counter),
+        Err(e) => {
+            error!("{e}. Fix or remove the file to start counting again.");
+            remove_pid_file();
+            exit(1);
+        },
+    };
+
+    let handler_counter = Arc::clone(&counter);
+    if let Err(e) = ctrlc::set_handler(move || {
+        handler_counter.flush();
         remove_pid_file();
         exit(0);
     }) {
-        debugprint!("Unable to set SIGINT handler. PID file won't be removed; ", e);
+        error!("Unable to set SIGINT/SIGTERM handler. Unsaved counts will be lost on exit; {e}");
+    }
+    if access_log && let Err(e) = accesslog::handle_sigusr1() {
+        warn!("Unable to set SIGUSR1 handler. The access log can't be reopened; {e}");
     }
 
-    let mut config: Config = Config::default();
-    load_config_from_file(&mut config, "config.toml");
+    // Uniques and history are rewritten whole, so they're only saved here 
+    // and on exit rather than along with every visit
+    let flush_counter = Arc::clone(&counter);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(flush_interval.unwrap_or(LISTS_SAVE_INTERVAL));
+        match flush_interval {
+            Some(_) => flush_counter.flush(),
+            None => flush_counter.save_lists(),
+        }
+    });
+
+    let expiry_counter = Arc::clone(&counter);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(EXPIRY_INTERVAL);
+        expiry_counter.clear_timedout();
+        expiry_counter.clear_rate_limits();
+        if let Some(log) = &expiry_counter.access_log {
+            log.reopen_if_requested();
+        }
+    });
+
+    if let Some(admin_addr) = admin_bind_addr {
+        info!("Admin API listening on {admin_addr}");
+        spawn_listener(&admin_addr, Listener::Admin, Arc::clone(&counter), (read_timeout, write_timeout));
+    }
+    if let Some(metrics_addr) = metrics_bind_addr {
+        info!("Metrics listening on {metrics_addr}");
+        spawn_listener(&metrics_addr, Listener::Metrics, Arc::clone(&counter), (read_timeout, write_timeout));
+    }
+
+    let pool = Pool::new(workers, max_connections, move |stream| {
+        counter.refresh_images();
+        counter.handle_connection(stream, Listener::Public);
+    });
 
-    println!("Listening on {}", config.bind_addr);
-
-    let mut counter = Counter::new(
-                            read_number(&config.counterfile).unwrap_or_default(), 
-                            config.counterfile.to_string(),
-                            config.image_dir,
-                            config.img_format,
-                            config.content_type,
-                            config.count_unique,
-                            config.timeout,
-                            config.blacklist,
-                            config.ua_list,
-                            config.allow_empty_ua
-                            );
-
-    let listener = match TcpListener::bind(config.bind_addr) {
+    let listener = match TcpListener::bind(bind_addr) {
         Err(err) => {
-            eprintln!("Unable to bind address! Error: {}", err);
+            error!("Unable to bind address! Error: {}", err);
             exit(1);
         },
         Ok(lst) => lst,
@@ -139,269 +304,764 @@ fn main() {
     for stream in listener.incoming() {
         let stream = match stream {
             Err(e) => {
-                eprintln!("Incoming connection error: {e}");
+                warn!("Incoming connection error: {e}");
                 continue;
             },
             Ok(stm) => stm,
         };
 
-        counter.clear_timedout();
-        counter.handle_connection(stream);
+        if let Err(e) = stream.set_read_timeout(Some(read_timeout))
+                              .and_then(|_| stream.set_write_timeout(Some(write_timeout))) {
+            warn!("Unable to set connection timeouts: {e}");
+        }
+
+        if let Err(mut stream) = pool.execute(stream) {
+            warn!("Too many connections; rejecting a new one");
+            if let Err(e) = respond_with_headers(&mut stream, SERVICE_UNAVAILABLE, &[("Retry-After", "1".to_owned())]) {
+                warn!("Error sending response: {e}");
+            }
+        }
     }
 
     remove_pid_file();
 }
 
-struct Counter {
+/// Which address a connection came in on.
+#[derive(Clone, Copy, PartialEq)]
+enum Listener {
+    /// `bind_addr`, serving everything.
+    Public,
+    /// `admin_bind_addr`, serving only the admin API.
+    Admin,
+    /// `metrics_bind_addr`, serving only `/metrics`.
+    Metrics,
+}
+
+/// Sets the log level from `--log-level` or, failing that, `NOBSCOUNT_LOG`.
+/// Returns whether either was given, in which case `log_level` from the
+/// config is ignored.
+fn set_log_level_from_args_or_env() -> bool {
+    let mut level = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--log-level") {
+            Some("") => args.next(),
+            Some(value) if value.starts_with('=') => Some(value[1..].to_owned()),
+            _ => {
+                eprintln!("Unknown argument {arg}. Usage: nobscount [--log-level error|warn|info|debug|trace]");
+                exit(2);
+            },
+        };
+        match value.as_deref().map(str::parse::<Level>) {
+            Some(Ok(parsed)) => level = Some(parsed),
+            Some(Err(e)) => {
+                eprintln!("{e}; use \"error\", \"warn\", \"info\", \"debug\" or \"trace\"");
+                exit(2);
+            },
+            None => {
+                eprintln!("--log-level needs a value");
+                exit(2);
+            },
+        }
+    }
+
+    if level.is_none() && let Ok(value) = std::env::var("NOBSCOUNT_LOG") {
+        match value.parse() {
+            Ok(parsed) => level = Some(parsed),
+            Err(e) => warn!("{e} in NOBSCOUNT_LOG; ignoring it"),
+        }
+    }
+
+    level.inspect(|level| util::set_log_level(*level)).is_some()
+}
+
+/// Accepts connections on an extra address in a thread of its own. These
+/// see little traffic, so they're handled one at a time.
+fn spawn_listener(addr: &str, kind: Listener, counter: Arc<Counter>, (read_timeout, write_timeout): (Duration, Duration)) {
+    let listener = match TcpListener::bind(addr) {
+        Err(err) => {
+            error!("Unable to bind address {addr}! Error: {}", err);
+            exit(1);
+        },
+        Ok(lst) => lst,
+    };
+
+    std::thread::spawn(move || for stream in listener.incoming() {
+        let stream = match stream {
+            Err(e) => {
+                warn!("Incoming connection error: {e}");
+                continue;
+            },
+            Ok(stm) => stm,
+        };
+        if let Err(e) = stream.set_read_timeout(Some(read_timeout))
+                              .and_then(|_| stream.set_write_timeout(Some(write_timeout))) {
+            warn!("Unable to set connection timeouts: {e}");
+        }
+        counter.handle_connection(stream, kind);
+    });
+}
+
+/// A single named counter: its value, where it's stored and who has already
+/// been counted.
+struct Tally {
     count: usize,
+    unsaved: usize,
+    /// When a visit was last counted; the counter file's modification time
+    /// until one is counted after startup.
+    last_increment: Option<SystemTime>,
     filepath: String,
-    image_dir: String,
-    img_format: String,
-    content_type: String,
-    uniques: HashMap<IpAddr, Instant>,
-    count_unique: bool,
-    timeout: u64,
-    blacklist: Vec<IpAddr>,
-    ua_list: Vec<Regex>,
-    allow_empty_ua: bool
+    uniques: Uniques,
+    /// Where `uniques` is saved; `None` unless unique visits are counted.
+    uniques_path: Option<String>,
+    /// Visits per hour and day, saved next to the counter file; `None` unless
+    /// `history` is enabled.
+    history: Option<History>,
+    /// Whether `uniques` or `history` changed since they were last saved.
+    lists_unsaved: bool,
+    /// Order of writes to the counter, uniques and history files.
+    slots: [Arc<Slot>; 3],
+}
+
+impl Tally {
+    fn load(filepath: String, config: &Config) -> Result<Self, String> {
+        let count = store::load_number(&filepath)?;
+        let uniques_path = (config.count_unique && config.hash_rotation.is_none()).then(|| format!("{filepath}.uniques"));
+        let uniques = match &uniques_path {
+            Some(path) => Uniques::load(path, Duration::from_secs(config.timeout), config.max_uniques),
+            None => Uniques::new(config.max_uniques, config.hash_rotation),
+        };
+
+        let last_increment = std::fs::metadata(&filepath).and_then(|m| m.modified()).ok();
+
+        let history = config.history.then(|| {
+            History::load(&history_path(&filepath), config.history_hours, config.history_days)
+        });
+
+        Ok(Tally { 
+            count, 
+            unsaved: 0, 
+            last_increment, 
+            filepath, 
+            uniques, 
+            uniques_path, 
+            history, 
+            lists_unsaved: false, 
+            slots: Default::default() 
+        })
+    }
+
+    /// Counts a visit. Once `flush_every` visits have piled up, returns the
+    /// value to write to disk.
+    fn increment(&mut self, flush_every: usize) -> Option<Snapshot> {
+        self.count += 1;
+        self.unsaved += 1;
+        self.last_increment = Some(SystemTime::now());
+        self.lists_unsaved = true;
+        if let Some(history) = &mut self.history {
+            history.record();
+        }
+
+        if self.unsaved >= flush_every {
+            self.save()
+        } else {
+            None
+        }
+    }
+
+    /// Overwrites the value, e.g. to correct it after a bot spike, and returns
+    /// it to write to disk right away.
+    fn set(&mut self, count: usize) -> Option<Snapshot> {
+        self.count = count;
+        self.unsaved += 1;
+        self.save()
+    }
+
+    fn clear_uniques(&mut self) -> Option<Snapshot> {
+        self.uniques.clear();
+        let path = self.uniques_path.clone()?;
+        Some(self.slots[1].snapshot(path, self.uniques.encode()))
+    }
+
+    /// Takes the value to write to disk, if it changed since the last write or
+    /// that write failed. Uniques and history are left to `save_lists`.
+    fn save(&mut self) -> Option<Snapshot> {
+        if self.unsaved == 0 && !self.slots[0].failed() {
+            return None;
+        }
+
+        self.unsaved = 0;
+        Some(self.slots[0].snapshot(self.filepath.clone(), self.count.to_string().into_bytes()))
+    }
+
+    /// Takes uniques and history to write to disk, if they changed since the
+    /// last write or that write failed.
+    fn save_lists(&mut self) -> Vec<Snapshot> {
+        if !self.lists_unsaved && !self.slots[1].failed() && !self.slots[2].failed() {
+            return Vec::new();
+        }
+        self.lists_unsaved = false;
+
+        let mut snapshots = Vec::new();
+        if let Some(path) = &self.uniques_path {
+            snapshots.push(self.slots[1].snapshot(path.clone(), self.uniques.encode()));
+        }
+        if let Some(history) = &self.history {
+            snapshots.push(self.slots[2].snapshot(history_path(&self.filepath), history.encode()));
+        }
+        snapshots
+    }
+}
+
+/// Counter state shared between worker threads.
+struct Counter {
+    tallies: Mutex<HashMap<String, Tally>>,
+    digit_set: Mutex<Arc<DigitSet>>,
+    images_checked: Mutex<Instant>,
+    increment_limiter: Option<RateLimiter>,
+    image_limiter: Option<RateLimiter>,
+    metrics: Metrics,
+    access_log: Option<AccessLog>,
+    config: Config,
 }
 
 impl Counter {
-    pub fn new(count: usize, filepath: String, image_dir: String, img_format: String, content_type: String, count_unique: bool, timeout: u64, blacklist: Vec<IpAddr>, ua_list: Vec<Regex>, allow_empty_ua: bool) -> Self {
-        Counter { count, filepath, image_dir, img_format, content_type, uniques: HashMap::new(), count_unique, timeout, blacklist, ua_list, allow_empty_ua }
+    pub fn new(config: Config) -> Result<Self, String> {
+        let mut tallies = HashMap::new();
+        tallies.insert(DEFAULT_COUNTER.to_owned(), Tally::load(config.counterfile.clone(), &config)?);
+
+        for name in config.counters.iter() {
+            if !tallies.contains_key(name) {
+                tallies.insert(name.clone(), Tally::load(counter_path(&config.counters_dir, name), &config)?);
+            }
+        }
+        if (!config.counters.is_empty() || config.create_counters) && 
+           let Err(e) = std::fs::create_dir_all(&config.counters_dir) {
+            error!("Unable to create counters directory {}: {e}", config.counters_dir);
+        }
+
+        let digit_set = match &config.image_dir {
+            Some(dir) => DigitSet::from_dir(dir, &config.img_format, &config.content_type).unwrap_or_else(|e| {
+                warn!("Unable to load images from {dir}: {e}; using the {} theme instead", config.theme.name);
+                DigitSet::from_theme(config.theme)
+            }),
+            None => DigitSet::from_theme(config.theme),
+        };
+
+        Ok(Counter { 
+            tallies: Mutex::new(tallies), 
+            digit_set: Mutex::new(Arc::new(digit_set)), 
+            images_checked: Mutex::new(Instant::now()), 
+            increment_limiter: config.increment_limit.map(RateLimiter::new),
+            image_limiter: config.image_limit.map(RateLimiter::new),
+            metrics: Metrics::default(),
+            access_log: config.access_log.clone().map(|path| AccessLog::new(path, config.access_log_format)),
+            config 
+        })
     }
 
-    pub fn clear_timedout(&mut self) {
-        let mut for_removal = Vec::new();
-        for (ip, timestamp) in self.uniques.iter() {
-            let elapsed = Instant::now().checked_duration_since(timestamp.clone());
-            if elapsed.is_some_and(|e| e.as_secs() >= self.timeout) {
-                debugprint!("Removed from uniques list: ", ip.to_string());
-                for_removal.push(ip.clone());
+    /// Looks up a counter by name and runs `f` on it while holding the lock.
+    /// If the config allows creating counters, one that isn't loaded yet is 
+    /// read from `counters_dir`, and with `create` it's started from zero if 
+    /// it has no file yet; either way only up to `max_counters`.
+    fn with_tally<R>(&self, name: &str, create: bool, f: impl FnOnce(&mut Tally) -> R) -> Option<R> {
+        let mut tallies = lock(&self.tallies);
+        if !tallies.contains_key(name) {
+            if !self.config.create_counters || !valid_counter_name(name) {
+                return None;
+            }
+            let path = counter_path(&self.config.counters_dir, name);
+            if !create && !std::path::Path::new(&path).exists() {
+                return None;
+            }
+            if tallies.len() >= self.config.max_counters {
+                info!("Not creating counter {name}: max_counters ({}) reached", self.config.max_counters);
+                return None;
             }
+
+            debug!("Creating counter {name}");
+            match Tally::load(path, &self.config) {
+                Ok(tally) => tallies.insert(name.to_owned(), tally),
+                Err(e) => {
+                    error!("{e}; refusing to serve counter {name}");
+                    return None;
+                },
+            };
+        }
+
+        tallies.get_mut(name).map(f)
+    }
+
+    fn digit_set(&self) -> Arc<DigitSet> {
+        Arc::clone(&lock(&self.digit_set))
+    }
+
+    /// Reloads digit images if any of them changed on disk. Checks at most
+    /// once per `IMAGES_CHECK_INTERVAL`.
+    pub fn refresh_images(&self) {
+        let Some(dir) = &self.config.image_dir else {
+            return;
+        };
+        {
+            let mut checked = lock(&self.images_checked);
+            if checked.elapsed() < IMAGES_CHECK_INTERVAL {
+                return;
+            }
+            *checked = Instant::now();
+        }
+
+        match images::dir_modified(dir, &self.config.img_format) {
+            Ok(modified) if modified > self.digit_set().modified => {
+                match DigitSet::from_dir(dir, &self.config.img_format, &self.config.content_type) {
+                    Ok(set) => {
+                        debug!("Reloaded images from {dir}");
+                        *lock(&self.digit_set) = Arc::new(set);
+                    },
+                    Err(e) => warn!("Error reloading images from {dir}: {e}; keeping old ones"),
+                }
+            },
+            Ok(_) => {},
+            Err(e) => warn!("Error checking images in {dir}: {e}; keeping old ones"),
+        }
+    }
+
+    /// Writes every counter with unsaved visits to disk. The files are 
+    /// written after releasing the lock, so requests don't wait on the disk.
+    pub fn flush(&self) {
+        let snapshots: Vec<Snapshot> = lock(&self.tallies).values_mut().flat_map(|tally| {
+            tally.save().into_iter().chain(tally.save_lists())
+        }).collect();
+        store::write_snapshots(snapshots);
+    }
+
+    pub fn save_lists(&self) {
+        let snapshots: Vec<Snapshot> = lock(&self.tallies).values_mut().flat_map(Tally::save_lists).collect();
+        store::write_snapshots(snapshots);
+    }
+
+    pub fn clear_timedout(&self) {
+        let timeout = Duration::from_secs(self.config.timeout);
+        for tally in lock(&self.tallies).values_mut() {
+            tally.uniques.clear_timedout(timeout);
         }
+    }
 
-        for ip in for_removal {
-            self.uniques.remove(&ip);
+    pub fn clear_rate_limits(&self) {
+        for limiter in [&self.increment_limiter, &self.image_limiter].into_iter().flatten() {
+            limiter.clear_idle();
         }
     }
 
-    pub fn handle_connection(&mut self, mut stream: TcpStream) {
-        let mut ip = stream.peer_addr().ok().map(|ip| ip.ip());
-        let reader = BufReader::new(&stream);
-        // let mut http_request = String::new(); 
-        let mut http_request = reader.lines()
-                                             .take_while(|line| line.as_ref().is_ok_and(|l| !l.is_empty()))
-                                             .map(|l| l.unwrap())
-                                             .collect::<Vec<String>>()
-                                             .join("\n");
+    /// Serves one connection and records it in the metrics and access log.
+    pub fn handle_connection(&self, stream: TcpStream, listener: Listener) {
+        let started = Instant::now();
+        let peer = stream.peer_addr().ok().map(|ip| ip.ip());
+        let mut stream = Connection::new(stream);
+        let mut reader = BufReader::new(DeadlineReader::new(stream.get_ref(), started + self.config.read_timeout));
+        let request = match http::read_request(&mut reader) {
+            Ok(request) => Some(request),
+            Err(ParseError::Io(e)) => {
+                debug!("Error reading request: {e}");
+                None
+            },
+            Err(ParseError::Malformed(reason)) => {
+                info!("Malformed request: {reason}");
+                if let Err(e) = respond(&mut stream, BAD_REQUEST, None) {
+                    warn!("Error sending response: {e}");
+                }
+                None
+            },
+            Err(ParseError::TooLarge(reason)) => {
+                info!("Request rejected: {reason}");
+                if let Err(e) = respond(&mut stream, HEADERS_TOO_LARGE, None) {
+                    warn!("Error sending response: {e}");
+                }
+                None
+            },
+        };
+
+        let header = self.config.client_ip_header;
+        let ip = match &request {
+            Some(request) => peer.map(|peer| net::client_ip(peer, header, request.header(header.name()), &self.config.trusted_proxies)),
+            None => peer,
+        };
+        let decision = request.as_ref().and_then(|request| self.serve(&mut stream, request, ip, listener));
 
-        debugprint!(http_request);
-        if let Some(realip) = check_x_real_ip(&http_request) {
-            ip = Some(realip);
+        self.metrics.observe(request.as_ref().map(|r| r.path.as_str()), stream.status(), started.elapsed());
+        if let Some(log) = &self.access_log {
+            log.write(&Entry { ip, request: request.as_ref(), status: stream.status(), bytes: stream.written(), decision });
         }
-        debugprint!(format!("New connection from {}!", ip.unwrap()));
+    }
+
+    /// Routes a request. The admin and metrics listeners only serve their own
+    /// endpoints. Returns what became of a hit on `/increment`.
+    fn serve(&self, stream: &mut Connection, request: &Request, ip: Option<IpAddr>, listener: Listener) -> Option<Decision> {
+        trace!("{} {} from {}", request.method, request.path, ip.map_or("unknown".to_owned(), |ip| ip.to_string()));
 
-        let allowed_useragent = allow_useragent(&http_request, &self.ua_list, self.allow_empty_ua);
+        let metrics_here = match listener {
+            Listener::Metrics => true,
+            Listener::Public => self.config.metrics && self.config.metrics_bind_addr.is_none(),
+            Listener::Admin => false,
+        };
+        if request.path == "/metrics" && metrics_here {
+            self.send_metrics(stream);
+            return None;
+        }
+        if request.path.starts_with("/admin/") && listener != Listener::Metrics {
+            self.handle_admin(stream, request, listener == Listener::Admin);
+            return None;
+        }
+        if listener != Listener::Public {
+            if let Err(e) = respond(stream, NOT_FOUND, None) {
+                warn!("Error sending response: {e}");
+            }
+            return None;
+        }
+
+        let useragent = request.header("User-Agent");
+        let allowed_useragent = allow_useragent(useragent, &self.config.ua_allowlist, &self.config.ua_list, self.config.allow_empty_ua);
         if !allowed_useragent {
-            let ua = http_request.lines()
-                                 .find_map(|l| l.strip_prefix("User-Agent: "))
-                                 .unwrap_or("[no user-agent]");
-            eprintln!("Connection filtered based on user-agent: {ua}");
+            let ua = useragent.unwrap_or("[no user-agent]");
+            info!("Connection filtered based on user-agent: {ua}");
         }
 
-        http_request = http_request.lines().take(1).collect();
-        let http_request = http_request.trim();
-        debugprint!(http_request);
+        let referer = request.header("Origin").filter(|o| *o != "null").or(request.header("Referer"));
+        let allowed_referer = allow_referer(referer, &self.config.allowed_referers, self.config.allow_empty_referer);
+        if !allowed_referer {
+            let referer = referer.unwrap_or("[no referer]");
+            info!("Connection filtered based on referer: {referer}");
+        }
 
-        if !http_request.starts_with("GET ") {
-            return;
+        if request.method != "GET" {
+            info!("Unsupported method: {}", request.method);
+            if let Err(e) = respond_with_headers(stream, METHOD_NOT_ALLOWED, &[("Allow", "GET".to_owned())]) {
+                warn!("Error sending response: {e}");
+            }
+            return None;
         }
 
-        if let Some(uri) = http_request.split_whitespace().nth(1) {
-            let tuple = uri.split_once('?');
-            let mut arg: Option<u8> = None;
-            let method = if let Some((m, a)) = tuple {
-                arg = parse_arg(a);
-                m
-            } else {
-                uri
-            };
+        let method = request.path.as_str();
+        let name = request.arg("id").unwrap_or(DEFAULT_COUNTER);
+
+        let limiter = match method {
+            "/increment" => self.increment_limiter.as_ref(),
+            "/get" | "/counter.svg" | "/count.json" | "/count.txt" | "/stats" => self.image_limiter.as_ref(),
+            _ => None,
+        };
+        if let Some(limiter) = limiter && 
+           let Some(ip) = ip && 
+           let Err(wait) = limiter.check(unique_key(ip, self.config.ipv6_unique_prefix)) {
+            info!("Rate limit exceeded by {ip} on {method}");
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            if let Err(e) = respond_with_headers(stream, TOO_MANY_REQUESTS, &[("Retry-After", retry_after.to_string())]) {
+                warn!("Error sending response: {e}");
+            }
+            if method != "/increment" {
+                return None;
+            }
+            self.metrics.filtered(Filter::RateLimit);
+            return Some(Decision::Filtered(Filter::RateLimit));
+        }
 
-            match method {
-                "/increment" => {
-                    if let Some(ip) = ip {
-                        if !self.blacklist.contains(&ip) && allowed_useragent {
-                            if self.count_unique {
-                                if !self.uniques.contains_key(&ip) {
-                                    self.increment_counter();
-                                    self.uniques.insert(ip, Instant::now());
-                                    debugprint!("Added to uniques list: ", ip.to_string());
-                                }
-                            } else {
-                                self.increment_counter();
+        match method {
+            "/increment" => {
+                let count_unique = self.config.count_unique;
+                let flush_every = self.config.flush_every;
+                let useragent = request.header("User-Agent");
+                let ipv6_unique_prefix = self.config.ipv6_unique_prefix;
+                let blacklisted = ip.is_some_and(|ip| !allow_ip(ip, self.config.ip_allowlist.as_ref(), &self.config.blacklist));
+                let filter = if blacklisted {
+                    Some(Filter::Blacklist)
+                } else if !allowed_useragent {
+                    Some(Filter::UserAgent)
+                } else if !allowed_referer {
+                    Some(Filter::Referer)
+                } else {
+                    None
+                };
+                // Filtered hits don't get to create counters and use up `max_counters`
+                let found = self.with_tally(name, filter.is_none(), |tally| {
+                    if let Some(ip) = ip && filter.is_none() {
+                        if count_unique {
+                            let ip = unique_key(ip, ipv6_unique_prefix);
+                            if tally.uniques.contains(ip, useragent) {
+                                return (Some(Filter::Unique), None);
                             }
+                            tally.uniques.insert(ip, useragent);
+                            debug!("Added to uniques list: {ip}");
                         }
+                        return (filter, tally.increment(flush_every));
                     }
-                    if let Err(e) = respond(&mut stream, OK, Some("text/javascript")) {
-                        eprintln!("Error sending OK response to /increment request: {e}");
+                    (filter, None)
+                });
+                let Some((filter, snapshot)) = found else {
+                    info!("Unknown counter: {name}");
+                    if let Err(e) = respond(stream, NOT_FOUND, None) {
+                        warn!("Error sending response: {e}");
                     }
-                },
-                "/get" => {
-                    match arg {
-                        Some(arg) => match arg {
-                            0 => {
-                                eprintln!("Argument cannot be equal 0");
-                                if let Err(e) = respond(&mut stream, BAD_REQUEST, None) {
-                                    eprintln!("Error sending response: {e}");
-                                }
-                            }
-                            _ => {
-                                self.send_counter_image(&mut stream, arg);
-                            }
+                    return None;
+                };
+                match filter {
+                    Some(filter) => self.metrics.filtered(filter),
+                    None => self.metrics.counted(),
+                }
+
+                if let Err(e) = respond(stream, OK, Some("text/javascript")) {
+                    warn!("Error sending OK response to /increment request: {e}");
+                }
+                store::write_snapshots(snapshot);
+                return Some(filter.map_or(Decision::Counted, Decision::Filtered));
+            },
+            "/get" => {
+                let arg = request.arg("n").and_then(|n| n.parse::<u8>().ok());
+                let count = self.with_tally(name, false, |t| t.count);
+                match (arg, count) {
+                    (_, None) => {
+                        info!("Unknown counter: {name}");
+                        if let Err(e) = respond(stream, NOT_FOUND, None) {
+                            warn!("Error sending response: {e}");
                         }
-                        None => {
-                            eprintln!("Unparsable argument or wrong argument name");
-                            if let Err(e) = respond(&mut stream, BAD_REQUEST, None) {
-                                eprintln!("Error sending response: {e}");
-                            }
+                    }
+                    (Some(0), _) => {
+                        info!("Argument cannot be equal 0");
+                        if let Err(e) = respond(stream, BAD_REQUEST, None) {
+                            warn!("Error sending response: {e}");
+                        }
+                    }
+                    (Some(arg), Some(count)) => {
+                        self.send_counter_image(stream, count, arg, request);
+                    }
+                    (None, _) => {
+                        info!("Unparsable argument or wrong argument name");
+                        if let Err(e) = respond(stream, BAD_REQUEST, None) {
+                            warn!("Error sending response: {e}");
                         }
                     }
                 }
-                _ => {
-                    eprintln!("Unknown method: {method}");
-                    if let Err(e) = respond(&mut stream, BAD_REQUEST, None) {
-                        eprintln!("Error sending response: {e}");
+            }
+            "/counter.svg" => {
+                let width = match request.arg("digits") {
+                    Some(d) => d.parse::<usize>().ok().filter(|d| *d <= MAX_DIGITS),
+                    None => Some(self.config.digits),
+                };
+                let count = self.with_tally(name, false, |t| t.count);
+                match (width, count) {
+                    (_, None) => {
+                        info!("Unknown counter: {name}");
+                        if let Err(e) = respond(stream, NOT_FOUND, None) {
+                            warn!("Error sending response: {e}");
+                        }
+                    }
+                    (Some(width), Some(count)) => {
+                        self.send_counter_svg(stream, count, width, request);
+                    }
+                    (None, _) => {
+                        info!("Unparsable `digits` argument");
+                        if let Err(e) = respond(stream, BAD_REQUEST, None) {
+                            warn!("Error sending response: {e}");
+                        }
                     }
                 }
             }
-        } else {
-            eprintln!("Malformed GET header: {http_request}");
-            if let Err(e) = respond(&mut stream, BAD_REQUEST, None) {
-                eprintln!("Error sending response: {e}");
+            "/count.json" | "/count.txt" => {
+                let stats = self.with_tally(name, false, |t| (t.count, t.uniques.len(), t.last_increment));
+                let Some((count, uniques, last_increment)) = stats else {
+                    info!("Unknown counter: {name}");
+                    if let Err(e) = respond(stream, NOT_FOUND, None) {
+                        warn!("Error sending response: {e}");
+                    }
+                    return None;
+                };
+
+                let mut headers = vec![("Cache-Control", self.config.cache_control.clone())];
+                headers.extend(self.cors_headers(request));
+                if method == "/count.txt" {
+                    send_body(stream, OK, "text/plain; charset=utf-8", count.to_string().as_bytes(), &headers);
+                } else {
+                    let last_increment = last_increment.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                                                       .map_or("null".to_owned(), |d| d.as_secs().to_string());
+                    let body = format!("{{\"name\":{},\"value\":{count},\"uniques\":{uniques},\"last_increment\":{last_increment}}}", 
+                                       json_string(name));
+                    send_body(stream, OK, "application/json", body.as_bytes(), &headers);
+                }
+            }
+            "/stats" => self.send_stats(stream, name, request),
+            _ => {
+                info!("Unknown method: {method}");
+                if let Err(e) = respond(stream, BAD_REQUEST, None) {
+                    warn!("Error sending response: {e}");
+                }
             }
         }
-    }
 
-    fn increment_counter(&mut self) {
-        self.count += 1;
+        None
+    }
 
-        let bytes = self.count.to_string();
-        if let Err(e) = std::fs::write(&self.filepath, bytes.as_bytes()) {
-            eprintln!("Error writing counter value to file! {e}");
-        }
+    fn send_metrics(&self, stream: &mut Connection) {
+        let body = {
+            let tallies = lock(&self.tallies);
+            let mut samples: Vec<TallySample> = tallies.iter().map(|(name, tally)| {
+                TallySample { name, count: tally.count, uniques: tally.uniques.len() }
+            }).collect();
+            samples.sort_by_key(|sample| sample.name);
+            self.metrics.render(&samples)
+        };
+        send_body(stream, OK, "text/plain; version=0.0.4; charset=utf-8", body.as_bytes(), &[("Cache-Control", "no-store".to_owned())]);
     }
 
-    fn send_counter_image(&self, stream: &mut TcpStream, no: u8) {
-        let mut counter = self.count;
+    fn send_counter_image(&self, stream: &mut Connection, count: usize, no: u8, request: &Request) {
+        let counter = 10usize.checked_pow((no - 1).into()).map_or(0, |place| count / place);
 
-        counter /= 10usize.pow((no-1).into());
+        let digit = if counter == 0 { None } else { Some((counter % 10) as u8) };
+        assert!(digit.is_none_or(|d| d < 10));
 
-        let digit;
-        if counter == 0 {
-            digit = None;
-        } else {
-            digit = Some(counter % 10);
+        if self.config.renderer == Renderer::Svg {
+            let mut style = self.config.svg_style.clone();
+            style.apply_args(&request.query);
+            let svg = render::draw_svg(&[digit], &style);
+            self.send_image(stream, "image/svg+xml", svg.as_bytes(), &images::etag(svg.as_bytes()), None, request);
+            return;
         }
-        assert!(digit.is_none_or(|d| d < 10));
 
-        let mut status = OK;
-        let mut stream = BufWriter::new(stream);
-        let digit = digit.map(|d| d.to_string()).unwrap_or("empty".to_owned());
-        let filebuf = match std::fs::read(format!("{}/{}.{}", self.image_dir, digit, self.img_format)) 
-        {
-            Err(e) => {
-                eprintln!("Error reading file! {e}");
-                status = INTERNAL_ERROR;
+        let digit_set = self.digit_set();
+        self.send_image(stream, 
+                        &digit_set.content_type, 
+                        digit_set.image(digit), 
+                        digit_set.etag(digit), 
+                        Some(digit_set.modified), 
+                        request);
+    }
 
-                Vec::new()
-            },
-            Ok(v) => v,
+    fn send_counter_svg(&self, stream: &mut Connection, count: usize, width: usize, request: &Request) {
+        let svg = if self.config.renderer == Renderer::Svg {
+            let mut style = self.config.svg_style.clone();
+            style.apply_args(&request.query);
+            render::draw_svg(&render::digits(count, width), &style)
+        } else {
+            let digit_set = self.digit_set();
+            let images = render::digits(count, width).into_iter()
+                                                     .map(|digit| digit_set.image(digit))
+                                                     .collect::<Vec<&[u8]>>();
+            match render::compose_svg(&images, &digit_set.content_type) {
+                Some(svg) => svg,
+                None => {
+                    error!("Unable to read digit image dimensions; is `img_format` a PNG, GIF or JPEG?");
+                    send_body(stream, INTERNAL_ERROR, "image/svg+xml", &[], &[]);
+                    return;
+                }
+            }
         };
-        let length = filebuf.len();
 
-        let headers = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n", status, self.content_type, length);
-        
-        if let Err(e) = stream.write(headers.as_bytes()) {
-            eprintln!("Error writing bytes to stream buffer! {e}");
-        } else {
-            if let Err(e) = stream.write(&filebuf) {
-                eprintln!("Error writing file to stream buffer! {e}");
+        self.send_image(stream, "image/svg+xml", svg.as_bytes(), &images::etag(svg.as_bytes()), None, request);
+    }
+
+    /// Sends visits per hour or day as JSON. `from` and `to` are Unix
+    /// timestamps or `YYYY-MM-DD` dates and default to the retained range.
+    fn send_stats(&self, stream: &mut Connection, name: &str, request: &Request) {
+        let granularity = match request.arg("granularity") {
+            None | Some("day") => Some(Granularity::Day),
+            Some("hour") => Some(Granularity::Hour),
+            Some(_) => None,
+        };
+        let parse_time = |arg: &str| match request.arg(arg) {
+            Some(t) => t.parse::<u64>().ok().or_else(|| util::parse_date(t)).map(Some),
+            None => Some(None),
+        };
+        let (Some(granularity), Some(from), Some(to)) = (granularity, parse_time("from"), parse_time("to")) else {
+            info!("Unparsable /stats arguments");
+            if let Err(e) = respond(stream, BAD_REQUEST, None) {
+                warn!("Error sending response: {e}");
             }
+            return;
+        };
+
+        let series = self.with_tally(name, false, |tally| {
+            tally.history.as_ref().map(|history| history.series(granularity, from.unwrap_or(0), to.unwrap_or(u64::MAX)))
+        });
+        let series = match series {
+            Some(Some(series)) => series,
+            Some(None) => {
+                info!("Stats requested, but `history` is disabled");
+                if let Err(e) = respond(stream, NOT_FOUND, None) {
+                    warn!("Error sending response: {e}");
+                }
+                return;
+            },
+            None => {
+                info!("Unknown counter: {name}");
+                if let Err(e) = respond(stream, NOT_FOUND, None) {
+                    warn!("Error sending response: {e}");
+                }
+                return;
+            },
+        };
+
+        let points: Vec<String> = series.into_iter().map(|(start, visits)| {
+            format!("{{\"start\":{start},\"time\":\"{}\",\"visits\":{visits}}}", util::iso_datetime(start))
+        }).collect();
+        let body = format!("{{\"name\":{},\"granularity\":\"{}\",\"series\":[{}]}}", 
+                           json_string(name), granularity.name(), points.join(","));
+
+        let mut headers = vec![("Cache-Control", self.config.cache_control.clone())];
+        headers.extend(self.cors_headers(request));
+        send_body(stream, OK, "application/json", body.as_bytes(), &headers);
+    }
+
+    /// CORS headers letting pages on `cors_origins` read the count.
+    fn cors_headers(&self, request: &Request) -> Vec<(&'static str, String)> {
+        let origins = &self.config.cors_origins;
+        if origins.iter().any(|o| o == "*") {
+            return vec![("Access-Control-Allow-Origin", "*".to_owned())];
+        }
+        if origins.is_empty() {
+            return Vec::new();
         }
 
-        if let Err(e) = stream.flush() {
-            eprintln!("Error flushing stream buffer! {e}");
+        let mut headers = vec![("Vary", "Origin".to_owned())];
+        if let Some(origin) = request.header("Origin") && 
+           origins.iter().any(|o| o.eq_ignore_ascii_case(origin)) {
+            headers.push(("Access-Control-Allow-Origin", origin.to_owned()));
         }
+        headers
     }
-}
 
-fn respond(stream: &mut TcpStream, code: &str, content_type: Option<&str>) -> std::io::Result<()> {
-    stream.write_all(format!("HTTP/1.1 {code}\r\n").as_bytes())?;
-    if let Some(ctype) = content_type {
-        stream.write_all(format!("Content-Type: {ctype}\r\n").as_bytes())?;
-    }
-    stream.write_all("\r\n".as_bytes())?;
-    
-    Ok(())
-}
+    /// Sends an image with caching headers, or `304 Not Modified` if the
+    /// client already has it.
+    fn send_image(&self, stream: &mut Connection, content_type: &str, body: &[u8], etag: &str, modified: Option<SystemTime>, request: &Request) {
+        let mut headers = vec![("ETag", etag.to_owned()), ("Cache-Control", self.config.cache_control.clone())];
+        if let Some(modified) = modified {
+            headers.push(("Last-Modified", util::http_date(modified)));
+        }
 
-fn parse_arg(arg: &str) -> Option<u8> {
-    // n=0
-    match arg.split_once('=') {
-        None => return None,
-        Some((name, value)) => {
-            if name != "n" {
-                return None;
+        if request.header("If-None-Match").is_some_and(|tags| etag_matches(tags, etag)) {
+            if let Err(e) = respond_with_headers(stream, NOT_MODIFIED, &headers) {
+                warn!("Error sending response: {e}");
             }
-
-            return value.parse::<u8>().ok();
+        } else {
+            send_body(stream, OK, content_type, body, &headers);
         }
     }
 }
 
-fn read_number(filepath: &str) -> Option<usize> {
-    let file = File::open(filepath);
-
-    let mut file = match file {
-        Err(_) => {
-            eprintln!("Unable to open file {filepath}");
-            return None;
-        },
-        Ok(f) => f,
-    }; 
-
-    let mut buf = Vec::new();
-    match file.read_to_end(&mut buf) {
-        Err(_) => {
-            eprintln!("Unable to read file {filepath}");
-            return None;
-        },
-        Ok(_) => {},
-    }
+fn valid_counter_name(name: &str) -> bool {
+    !name.is_empty() && 
+    name.len() <= MAX_NAME_LEN && 
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
 
-    let snum = match from_utf8(&buf) {
-        Err(_) => {
-            eprintln!("Unable to parse file {filepath} to UTF-8");
-            return None;
-        },
-        Ok(s) => s,
-    };
+fn history_path(counterfile: &str) -> String {
+    format!("{counterfile}.history")
+}
 
-    match snum.parse::<usize>() {
-        Err(_) => {
-            eprintln!("Unable to parse value from {filepath}");
-            return None;
-        },
-        Ok(v) => return Some(v),
-    }
+fn counter_path(counters_dir: &str, name: &str) -> String {
+    format!("{counters_dir}/{name}.bin")
 }
 
 fn load_config_from_file(config: &mut Config, filepath: &str) {
     let file_contents = match std::fs::read_to_string(filepath) {
         Ok(contents) => contents,
         Err(e) => {
-            eprintln!("Error reading config: {e}; Using default settings");
+            warn!("Error reading config: {e}; Using default settings");
             return;
         },
     };
@@ -411,11 +1071,107 @@ fn load_config_from_file(config: &mut Config, filepath: &str) {
             if fileconf.contains_key("counterfile") && fileconf["counterfile"].is_str() {
                 config.counterfile = fileconf["counterfile"].as_str().unwrap().to_owned();
             }
+            if fileconf.contains_key("counters_dir") && fileconf["counters_dir"].is_str() {
+                config.counters_dir = fileconf["counters_dir"].as_str().unwrap().to_owned();
+            }
+            if fileconf.contains_key("flush_every") && fileconf["flush_every"].is_integer() {
+                config.flush_every = fileconf["flush_every"].as_integer().unwrap().max(1) as usize;
+            }
+            if fileconf.contains_key("flush_interval") && fileconf["flush_interval"].is_integer() {
+                let secs = fileconf["flush_interval"].as_integer().unwrap();
+                config.flush_interval = (secs > 0).then(|| Duration::from_secs(secs as u64));
+                // An interval on its own replaces per-visit writes
+                if config.flush_interval.is_some() && !fileconf.contains_key("flush_every") {
+                    config.flush_every = usize::MAX;
+                }
+            }
+            if fileconf.contains_key("counters") && fileconf["counters"].is_array() {
+                for name in fileconf["counters"].as_array().unwrap().iter() {
+                    match name.as_str() {
+                        Some(name) if valid_counter_name(name) => config.counters.push(name.to_owned()),
+                        _ => warn!("A counter name {name} isn't valid (use letters, digits, `-` and `_`); check config!"),
+                    }
+                }
+            }
+            if fileconf.contains_key("create_counters") && fileconf["create_counters"].is_bool() {
+                config.create_counters = fileconf["create_counters"].as_bool().unwrap();
+            }
+            if fileconf.contains_key("max_counters") && fileconf["max_counters"].is_integer() {
+                config.max_counters = fileconf["max_counters"].as_integer().unwrap().max(1) as usize;
+            }
             if fileconf.contains_key("bind_addr") && fileconf["bind_addr"].is_str() {
                 config.bind_addr = fileconf["bind_addr"].as_str().unwrap().to_owned();
             }
+            if fileconf.contains_key("admin_bind_addr") && fileconf["admin_bind_addr"].is_str() {
+                config.admin_bind_addr = Some(fileconf["admin_bind_addr"].as_str().unwrap().to_owned());
+            }
+            if fileconf.contains_key("history") && fileconf["history"].is_bool() {
+                config.history = fileconf["history"].as_bool().unwrap();
+            }
+            if fileconf.contains_key("history_hours") && fileconf["history_hours"].is_integer() {
+                config.history_hours = fileconf["history_hours"].as_integer().unwrap().clamp(1, MAX_HISTORY_BUCKETS) as u32;
+            }
+            if fileconf.contains_key("history_days") && fileconf["history_days"].is_integer() {
+                config.history_days = fileconf["history_days"].as_integer().unwrap().clamp(1, MAX_HISTORY_BUCKETS) as u32;
+            }
+            if fileconf.contains_key("cors_origins") && fileconf["cors_origins"].is_array() {
+                for origin in fileconf["cors_origins"].as_array().unwrap().iter() {
+                    match origin.as_str() {
+                        Some(origin) => config.cors_origins.push(origin.trim().trim_end_matches('/').to_owned()),
+                        None => warn!("A CORS origin {origin} isn't a valid string; check config!"),
+                    }
+                }
+            }
+            if fileconf.contains_key("access_log") && fileconf["access_log"].is_str() {
+                config.access_log = Some(fileconf["access_log"].as_str().unwrap().to_owned());
+            }
+            if fileconf.contains_key("access_log_format") && fileconf["access_log_format"].is_str() {
+                match fileconf["access_log_format"].as_str().unwrap().parse::<LogFormat>() {
+                    Ok(format) => config.access_log_format = format,
+                    Err(e) => warn!("{e}; use \"common\", \"combined\" or \"json\""),
+                }
+            }
+            if fileconf.contains_key("log_level") && fileconf["log_level"].is_str() {
+                match fileconf["log_level"].as_str().unwrap().parse::<Level>() {
+                    Ok(level) => config.log_level = Some(level),
+                    Err(e) => warn!("{e}; use \"error\", \"warn\", \"info\", \"debug\" or \"trace\""),
+                }
+            }
+            if fileconf.contains_key("metrics") && fileconf["metrics"].is_bool() {
+                config.metrics = fileconf["metrics"].as_bool().unwrap();
+            }
+            if fileconf.contains_key("metrics_bind_addr") && fileconf["metrics_bind_addr"].is_str() {
+                config.metrics_bind_addr = Some(fileconf["metrics_bind_addr"].as_str().unwrap().to_owned());
+            }
+            if fileconf.contains_key("admin_token") && fileconf["admin_token"].is_str() {
+                let token = fileconf["admin_token"].as_str().unwrap().trim();
+                if token.len() >= MIN_ADMIN_TOKEN_LEN {
+                    config.admin_token = Some(token.to_owned());
+                } else {
+                    warn!("Admin token must be at least {MIN_ADMIN_TOKEN_LEN} characters long; admin API disabled");
+                }
+            }
+            if fileconf.contains_key("workers") && fileconf["workers"].is_integer() {
+                config.workers = fileconf["workers"].as_integer().unwrap().max(1) as usize;
+            }
+            if fileconf.contains_key("max_connections") && fileconf["max_connections"].is_integer() {
+                config.max_connections = fileconf["max_connections"].as_integer().unwrap().max(1) as usize;
+            }
+            if fileconf.contains_key("read_timeout") && fileconf["read_timeout"].is_integer() {
+                config.read_timeout = Duration::from_secs(fileconf["read_timeout"].as_integer().unwrap().max(1) as u64);
+            }
+            if fileconf.contains_key("write_timeout") && fileconf["write_timeout"].is_integer() {
+                config.write_timeout = Duration::from_secs(fileconf["write_timeout"].as_integer().unwrap().max(1) as u64);
+            }
             if fileconf.contains_key("image_dir") && fileconf["image_dir"].is_str() {
-                config.image_dir = fileconf["image_dir"].as_str().unwrap().to_owned();
+                config.image_dir = Some(fileconf["image_dir"].as_str().unwrap().to_owned());
+            }
+            if fileconf.contains_key("theme") && fileconf["theme"].is_str() {
+                let name = fileconf["theme"].as_str().unwrap();
+                match images::theme(name) {
+                    Some(theme) => config.theme = theme,
+                    None => warn!("Unknown theme {name}; check config!"),
+                }
             }
             if fileconf.contains_key("img_format") && fileconf["img_format"].is_str() {
                 config.img_format = fileconf["img_format"].as_str().unwrap().to_owned();
@@ -423,12 +1179,57 @@ fn load_config_from_file(config: &mut Config, filepath: &str) {
             if fileconf.contains_key("content_type") && fileconf["content_type"].is_str() {
                 config.content_type = fileconf["content_type"].as_str().unwrap().to_owned();
             }
+            if fileconf.contains_key("cache_control") && fileconf["cache_control"].is_str() {
+                config.cache_control = fileconf["cache_control"].as_str().unwrap().to_owned();
+            }
+            if fileconf.contains_key("digits") && fileconf["digits"].is_integer() {
+                config.digits = (fileconf["digits"].as_integer().unwrap() as usize).min(MAX_DIGITS);
+            }
+            if fileconf.contains_key("renderer") && fileconf["renderer"].is_str() {
+                match fileconf["renderer"].as_str().unwrap() {
+                    "images" => config.renderer = Renderer::Images,
+                    "svg" => config.renderer = Renderer::Svg,
+                    other => warn!("Unknown renderer {other}; use \"images\" or \"svg\""),
+                }
+            }
+            if fileconf.contains_key("font_family") && fileconf["font_family"].is_str() {
+                config.svg_style.font_family = fileconf["font_family"].as_str().unwrap().to_owned();
+            }
+            if fileconf.contains_key("font_size") && fileconf["font_size"].is_integer() {
+                config.svg_style.font_size = fileconf["font_size"].as_integer().unwrap().clamp(0, render::MAX_FONT_SIZE.into()) as u32;
+            }
+            if fileconf.contains_key("fg_color") && fileconf["fg_color"].is_str() {
+                config.svg_style.fg_color = fileconf["fg_color"].as_str().unwrap().to_owned();
+            }
+            if fileconf.contains_key("bg_color") && fileconf["bg_color"].is_str() {
+                config.svg_style.bg_color = fileconf["bg_color"].as_str().unwrap().to_owned();
+            }
+            if fileconf.contains_key("border") && fileconf["border"].is_integer() {
+                config.svg_style.border = fileconf["border"].as_integer().unwrap().clamp(0, render::MAX_FONT_SIZE.into()) as u32;
+            }
+            if fileconf.contains_key("border_color") && fileconf["border_color"].is_str() {
+                config.svg_style.border_color = fileconf["border_color"].as_str().unwrap().to_owned();
+            }
+            if fileconf.contains_key("padding") && fileconf["padding"].is_integer() {
+                config.svg_style.padding = fileconf["padding"].as_integer().unwrap().clamp(0, render::MAX_FONT_SIZE.into()) as u32;
+            }
             if fileconf.contains_key("count_unique") && fileconf["count_unique"].is_bool() {
                 config.count_unique = fileconf["count_unique"].as_bool().unwrap().to_owned();
             }
             if fileconf.contains_key("timeout") && fileconf["timeout"].is_integer() {
                 config.timeout = fileconf["timeout"].as_integer().unwrap().to_owned() as u64;
             }
+            if fileconf.contains_key("max_uniques") && fileconf["max_uniques"].is_integer() {
+                config.max_uniques = fileconf["max_uniques"].as_integer().unwrap().max(1) as usize;
+            }
+            if fileconf.contains_key("hash_uniques") && fileconf["hash_uniques"].is_bool() && 
+               fileconf["hash_uniques"].as_bool().unwrap() {
+                config.hash_rotation = Some(Duration::from_secs(SALT_ROTATION));
+            }
+            if fileconf.contains_key("salt_rotation") && fileconf["salt_rotation"].is_integer() && 
+               config.hash_rotation.is_some() {
+                config.hash_rotation = Some(Duration::from_secs(fileconf["salt_rotation"].as_integer().unwrap().max(1) as u64));
+            }
             if fileconf.contains_key("blacklist") && 
             fileconf["blacklist"].is_array() &&
             fileconf["blacklist"].as_array().unwrap().iter().all(|v| v.is_str()) {
@@ -436,69 +1237,176 @@ fn load_config_from_file(config: &mut Config, filepath: &str) {
                                                         .unwrap()
                                                         .iter() 
                 {
-                    let ip = ip_str.as_str().map(|ip| ip.parse::<IpAddr>().ok());
-                    if let Some(ip_parsed) = ip {
-                        if let Some(ip_valid) = ip_parsed {
-                            debugprint!("Adding IP", ip_valid);
-                            config.blacklist.push(ip_valid);
-                        } else {
-                            eprintln!("A blacklist IP {ip_str} isn't a valid IP; check config!");
-                            continue;
-                        }
-                    } else {
-                        eprintln!("A blacklist IP {ip_str} isn't a valid string; check config!");
-                        continue;
+                    match ip_str.as_str().unwrap().parse::<IpNet>() {
+                        Ok(net) => {
+                            debug!("Adding IP range {net}");
+                            config.blacklist.insert(net);
+                        },
+                        Err(e) => warn!("Invalid blacklist entry {e}; check config!"),
                     }
                 }
             }
+            if fileconf.contains_key("trusted_proxies") && fileconf["trusted_proxies"].is_array() {
+                config.trusted_proxies = PrefixSet::default();
+                for net in fileconf["trusted_proxies"].as_array().unwrap().iter() {
+                    match net.as_str().map(|n| n.parse::<IpNet>()) {
+                        Some(Ok(net)) => config.trusted_proxies.insert(net),
+                        Some(Err(e)) => warn!("Invalid trusted proxy {e}; check config!"),
+                        None => warn!("A trusted proxy {net} isn't a valid string; check config!"),
+                    }
+                }
+            }
+            if fileconf.contains_key("client_ip_header") && fileconf["client_ip_header"].is_str() {
+                match fileconf["client_ip_header"].as_str().unwrap().parse::<ForwardedHeader>() {
+                    Ok(header) => config.client_ip_header = header,
+                    Err(e) => warn!("Invalid client_ip_header: {e}; use \"X-Real-IP\", \"X-Forwarded-For\" or \"Forwarded\""),
+                }
+            }
+            if fileconf.contains_key("ip_allowlist") && fileconf["ip_allowlist"].is_array() {
+                let mut allowlist = PrefixSet::default();
+                for net in fileconf["ip_allowlist"].as_array().unwrap().iter() {
+                    match net.as_str().map(|n| n.parse::<IpNet>()) {
+                        Some(Ok(net)) => allowlist.insert(net),
+                        Some(Err(e)) => warn!("Invalid allowlist entry {e}; check config!"),
+                        None => warn!("An allowlist entry {net} isn't a valid string; check config!"),
+                    }
+                }
+                config.ip_allowlist = Some(allowlist);
+            }
+            if fileconf.contains_key("ipv6_unique_prefix") && fileconf["ipv6_unique_prefix"].is_integer() {
+                config.ipv6_unique_prefix = fileconf["ipv6_unique_prefix"].as_integer().unwrap().clamp(1, 128) as u8;
+            }
+            let mut deny_list = Vec::new();
             if fileconf.contains_key("useragent_regexes") && 
             fileconf["useragent_regexes"].is_array() 
             {
                 for re in fileconf["useragent_regexes"].as_array().unwrap().iter() {
                     if re.is_str() {
                         let regex = re.as_str().unwrap();
-                        if let Ok(regex) = Regex::new(&regex) {
-                            config.ua_list.push(regex);
+                        if Regex::new(regex).is_ok() {
+                            deny_list.push(regex.to_owned());
                         } else {
-                            eprintln!("Not a valid regex: {regex}; check config!");
+                            warn!("Not a valid regex: {regex}; check config!");
                         }
                     }
                 }
             }
+            if fileconf.contains_key("filter_known_bots") && 
+            fileconf["filter_known_bots"].as_bool() == Some(true) 
+            {
+                deny_list.extend(known_bots());
+            }
+            config.ua_list = regex_set(&deny_list);
+
+            let mut allow_list = Vec::new();
+            if fileconf.contains_key("useragent_allowlist") && 
+            fileconf["useragent_allowlist"].is_array() 
+            {
+                for re in fileconf["useragent_allowlist"].as_array().unwrap().iter() {
+                    if re.is_str() {
+                        let regex = re.as_str().unwrap();
+                        if Regex::new(regex).is_ok() {
+                            allow_list.push(regex.to_owned());
+                        } else {
+                            warn!("Not a valid regex: {regex}; check config!");
+                        }
+                    }
+                }
+            }
+            config.ua_allowlist = regex_set(&allow_list);
             if fileconf.contains_key("allow_empty_uas") && fileconf["allow_empty_uas"].is_bool() {
                 config.allow_empty_ua = fileconf["allow_empty_uas"].as_bool().unwrap();
             }
+            if fileconf.contains_key("allowed_referers") && fileconf["allowed_referers"].is_array() {
+                for host in fileconf["allowed_referers"].as_array().unwrap().iter() {
+                    match host.as_str() {
+                        Some(host) => config.allowed_referers.push(host.trim().to_ascii_lowercase()),
+                        None => warn!("An allowed referer {host} isn't a valid string; check config!"),
+                    }
+                }
+            }
+            if fileconf.contains_key("allow_empty_referers") && fileconf["allow_empty_referers"].is_bool() {
+                config.allow_empty_referer = fileconf["allow_empty_referers"].as_bool().unwrap();
+            }
+            config.increment_limit = rate_limit(&fileconf, "increment_limit", "increment_burst");
+            config.image_limit = rate_limit(&fileconf, "image_limit", "image_burst");
         },
-        Err(e) => eprintln!("Error parsing config: {e}; using default settings"),
+        Err(e) => warn!("Error parsing config: {e}; using default settings"),
     }
 }
 
-fn check_x_real_ip(http_request: &str) -> Option<IpAddr> {
-    let mut realip: Option<&str> = None;
-    for line in http_request.lines() {
-        if line.starts_with("X-Real-IP: ") {
-            realip = line.strip_prefix("X-Real-IP: ").map(|s| s.trim());
-            break;
-        } 
-    }
+/// Reads a per-minute limit and its burst size; a missing or zero limit means
+/// no limit, and the burst defaults to the per-minute limit.
+fn rate_limit(fileconf: &Table, limit_key: &str, burst_key: &str) -> Option<Limit> {
+    let per_minute = fileconf.get(limit_key)?.as_integer().filter(|l| *l > 0)?.min(u32::MAX as i64) as u32;
+    let burst = match fileconf.get(burst_key).and_then(|b| b.as_integer()) {
+        Some(burst) => burst.clamp(1, u32::MAX as i64) as u32,
+        None => per_minute,
+    };
+    Some(Limit { per_minute, burst })
+}
 
-    if let Some(ip) = realip {
-        ip.parse().ok()
-    } else {
-        None
+/// Address a visitor is remembered by for unique counting: IPv6 addresses are
+/// cut to `ipv6_prefix` bits, so a machine rotating through privacy addresses
+/// in one network counts once.
+fn unique_key(ip: IpAddr, ipv6_prefix: u8) -> IpAddr {
+    match ip.to_canonical() {
+        ip @ IpAddr::V6(_) => net::mask(ip, ipv6_prefix),
+        ip => ip,
     }
 }
 
-fn allow_useragent(http_request: &str, re_list: &[Regex], allow_empty: bool) -> bool {
-    let useragent = http_request.lines()
-                                              .find(|line| line.starts_with("User-Agent: "));
+/// An IP is counted if it's in the allowlist (when there is one) and not in the
+/// blacklist; the blacklist wins when both match.
+fn allow_ip(ip: IpAddr, allowlist: Option<&PrefixSet>, blacklist: &PrefixSet) -> bool {
+    allowlist.is_none_or(|allow| allow.contains(ip)) && !blacklist.contains(ip)
+}
 
-    if useragent.is_none() {
-        return allow_empty;
+/// A user-agent is counted if it matches the allowlist (when it's not empty)
+/// and none of the deny regexes; deny wins when both match. Requests without 
+/// a user-agent are counted only with `allow_empty`.
+fn allow_useragent(useragent: Option<&str>, allow_list: &RegexSet, deny_list: &RegexSet, allow_empty: bool) -> bool {
+    match useragent {
+        None => allow_empty,
+        Some(useragent) => {
+            (allow_list.is_empty() || allow_list.is_match(useragent)) && !deny_list.is_match(useragent)
+        },
     }
+}
+
+/// A hit is counted if its `Origin` (or `Referer`) host is one of `allowed`,
+/// where `*.example.com` also matches any subdomain. An empty list allows any
+/// site; hits without either header are counted only with `allow_empty`.
+fn allow_referer(referer: Option<&str>, allowed: &[String], allow_empty: bool) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+    let Some(referer) = referer.filter(|r| !r.is_empty()) else {
+        return allow_empty;
+    };
+    let Some(host) = url_host(referer) else {
+        return false;
+    };
 
-    let useragent = useragent.unwrap().strip_prefix("User-Agent: ").unwrap();
+    allowed.iter().any(|pattern| match pattern.strip_prefix("*.") {
+        Some(domain) => host == domain || host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+        None => host == *pattern,
+    })
+}
 
-    !re_list.iter()
-            .any(|re| re.is_match(useragent))
-}
\ No newline at end of file
+/// Patterns from the built-in crawler list, made case-insensitive.
+fn known_bots() -> impl Iterator<Item = String> {
+    KNOWN_BOTS.lines()
+              .map(str::trim)
+              .filter(|line| !line.is_empty() && !line.starts_with('#'))
+              .map(|line| format!("(?i){line}"))
+}
+
+/// Compiles already validated patterns into one set, so a user-agent is
+/// checked against all of them in a single pass.
+fn regex_set(patterns: &[String]) -> RegexSet {
+    RegexSet::new(patterns).unwrap_or_else(|e| {
+        warn!("Unable to compile user-agent regexes: {e}; check config!");
+        RegexSet::empty()
+    })
+}
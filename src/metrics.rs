@@ -0,0 +1,152 @@
+use std::{fmt::Write, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+
+/// Why a hit on `/increment` wasn't counted.
+#[derive(Clone, Copy)]
+pub enum Filter {
+    Blacklist,
+    UserAgent,
+    Referer,
+    Unique,
+    RateLimit,
+}
+
+impl Filter {
+    const ALL: [Filter; 5] = [Filter::Blacklist, Filter::UserAgent, Filter::Referer, Filter::Unique, Filter::RateLimit];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Filter::Blacklist => "blacklist",
+            Filter::UserAgent => "useragent",
+            Filter::Referer => "referer",
+            Filter::Unique => "unique",
+            Filter::RateLimit => "ratelimit",
+        }
+    }
+}
+
+/// Routes latency is tracked for; anything else is `other`.
+const ROUTES: [&str; 9] = ["/increment", "/get", "/counter.svg", "/count.json", "/count.txt", "/stats", "/metrics", "/admin", "other"];
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not yet cumulative; the last one is `+Inf`.
+    buckets:    [AtomicU64; BUCKETS.len() + 1],
+    count:      AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|bound| secs <= *bound).unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Request statistics in Prometheus terms, updated lock-free by the workers.
+#[derive(Default)]
+pub struct Metrics {
+    counted:       AtomicU64,
+    filtered:      [AtomicU64; Filter::ALL.len()],
+    images_served: AtomicU64,
+    /// Responses by status class, 1xx to 5xx.
+    responses:     [AtomicU64; 5],
+    latency:       [Histogram; ROUTES.len()],
+}
+
+/// Per-counter values sampled when metrics are scraped.
+pub struct TallySample<'a> {
+    pub name:    &'a str,
+    pub count:   usize,
+    pub uniques: usize,
+}
+
+impl Metrics {
+    pub fn counted(&self) {
+        self.counted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn filtered(&self, filter: Filter) {
+        self.filtered[filter as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a finished request. `path` is `None` if the request couldn't
+    /// be parsed, and `status` is `None` if no response was sent.
+    pub fn observe(&self, path: Option<&str>, status: Option<u16>, elapsed: Duration) {
+        let route = match path {
+            Some(path) if path.starts_with("/admin/") => "/admin",
+            Some(path) => path,
+            None => "other",
+        };
+        let route = ROUTES.iter().position(|r| *r == route).unwrap_or(ROUTES.len() - 1);
+        self.latency[route].observe(elapsed);
+
+        if let Some(status) = status && (100..600).contains(&status) {
+            self.responses[status as usize / 100 - 1].fetch_add(1, Ordering::Relaxed);
+            if matches!(path, Some("/get" | "/counter.svg")) && matches!(status, 200 | 304) {
+                self.images_served.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Renders everything in the Prometheus text exposition format.
+    pub fn render(&self, tallies: &[TallySample]) -> String {
+        let mut out = String::new();
+
+        // A gauge, since the admin API can set the count lower
+        out.push_str("# HELP nobscount_visits Current value of each counter.\n# TYPE nobscount_visits gauge\n");
+        for tally in tallies {
+            let _ = writeln!(out, "nobscount_visits{{counter=\"{}\"}} {}", escape_label(tally.name), tally.count);
+        }
+
+        out.push_str("# HELP nobscount_uniques Visitors currently remembered for unique counting.\n# TYPE nobscount_uniques gauge\n");
+        for tally in tallies {
+            let _ = writeln!(out, "nobscount_uniques{{counter=\"{}\"}} {}", escape_label(tally.name), tally.uniques);
+        }
+
+        out.push_str("# HELP nobscount_counted_increments_total Increments counted since startup.\n");
+        out.push_str("# TYPE nobscount_counted_increments_total counter\n");
+        let _ = writeln!(out, "nobscount_counted_increments_total {}", self.counted.load(Ordering::Relaxed));
+
+        out.push_str("# HELP nobscount_filtered_increments_total Increments not counted, by reason.\n");
+        out.push_str("# TYPE nobscount_filtered_increments_total counter\n");
+        for filter in Filter::ALL {
+            let value = self.filtered[filter as usize].load(Ordering::Relaxed);
+            let _ = writeln!(out, "nobscount_filtered_increments_total{{reason=\"{}\"}} {value}", filter.label());
+        }
+
+        out.push_str("# HELP nobscount_images_served_total Counter images served, including 304 responses.\n");
+        out.push_str("# TYPE nobscount_images_served_total counter\n");
+        let _ = writeln!(out, "nobscount_images_served_total {}", self.images_served.load(Ordering::Relaxed));
+
+        out.push_str("# HELP nobscount_responses_total HTTP responses sent, by status class.\n# TYPE nobscount_responses_total counter\n");
+        for (i, responses) in self.responses.iter().enumerate() {
+            let _ = writeln!(out, "nobscount_responses_total{{class=\"{}xx\"}} {}", i + 1, responses.load(Ordering::Relaxed));
+        }
+
+        out.push_str("# HELP nobscount_request_duration_seconds Time spent handling requests, by route.\n");
+        out.push_str("# TYPE nobscount_request_duration_seconds histogram\n");
+        for (route, histogram) in ROUTES.iter().zip(self.latency.iter()) {
+            let mut cumulative = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let bound = BUCKETS.get(i).map_or("+Inf".to_owned(), |b| b.to_string());
+                let _ = writeln!(out, "nobscount_request_duration_seconds_bucket{{route=\"{route}\",le=\"{bound}\"}} {cumulative}");
+            }
+            let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            let _ = writeln!(out, "nobscount_request_duration_seconds_sum{{route=\"{route}\"}} {sum}");
+            let _ = writeln!(out, "nobscount_request_duration_seconds_count{{route=\"{route}\"}} {}", histogram.count.load(Ordering::Relaxed));
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,367 @@
+use std::{fmt, net::{IpAddr, Ipv4Addr, Ipv6Addr}, str::FromStr};
+
+/// An address range in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/64`.
+/// A plain address is a range of one.
+#[derive(Clone, Copy)]
+pub struct IpNet {
+    addr:   IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = addr.trim().parse::<IpAddr>().map_err(|e| format!("{s}: {e}"))?.to_canonical();
+        let max = max_prefix(&addr);
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().ok().filter(|p| *p <= max).ok_or(format!("{s}: invalid prefix length"))?,
+            None => max,
+        };
+
+        Ok(IpNet { addr: mask(addr, prefix), prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// A set of address ranges, matched with a binary trie per address family.
+/// Lookups take at most 32 (IPv4) or 128 (IPv6) steps regardless of how many
+/// ranges the set holds.
+#[derive(Default)]
+pub struct PrefixSet {
+    v4: Trie,
+    v6: Trie,
+}
+
+impl PrefixSet {
+    pub fn insert(&mut self, net: IpNet) {
+        let (trie, bits) = self.trie_for(net.addr);
+        trie.insert(bits, net.prefix);
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(v4) => self.v4.contains(u32::from(v4) as u128, 32),
+            IpAddr::V6(v6) => self.v6.contains(u128::from(v6), 128),
+        }
+    }
+
+    fn trie_for(&mut self, ip: IpAddr) -> (&mut Trie, u128) {
+        match ip {
+            IpAddr::V4(v4) => (&mut self.v4, (u32::from(v4) as u128) << 96),
+            IpAddr::V6(v6) => (&mut self.v6, u128::from(v6)),
+        }
+    }
+}
+
+impl FromIterator<IpNet> for PrefixSet {
+    fn from_iter<I: IntoIterator<Item = IpNet>>(iter: I) -> Self {
+        let mut set = PrefixSet::default();
+        for net in iter {
+            set.insert(net);
+        }
+        set
+    }
+}
+
+#[derive(Default)]
+struct Trie {
+    /// Child node indices for bit 0 and 1; 0 means no child, since the root
+    /// can't be anyone's child.
+    children: Vec<[usize; 2]>,
+    /// Whether a range ends at the node.
+    terminal: Vec<bool>,
+}
+
+impl Trie {
+    /// Adds a range given as its leading `prefix` bits, most significant bit
+    /// of `bits` first.
+    fn insert(&mut self, bits: u128, prefix: u8) {
+        if self.children.is_empty() {
+            self.children.push([0, 0]);
+            self.terminal.push(false);
+        }
+
+        let mut node = 0;
+        for i in 0..prefix {
+            if self.terminal[node] {
+                // A shorter range already covers this one
+                return;
+            }
+            let bit = (bits >> (127 - i) & 1) as usize;
+            if self.children[node][bit] == 0 {
+                self.children.push([0, 0]);
+                self.terminal.push(false);
+                self.children[node][bit] = self.children.len() - 1;
+            }
+            node = self.children[node][bit];
+        }
+        self.terminal[node] = true;
+    }
+
+    /// Checks whether any stored range covers an address of `len` bits.
+    fn contains(&self, addr: u128, len: u8) -> bool {
+        if self.children.is_empty() {
+            return false;
+        }
+
+        let bits = addr << (128 - len as u32);
+        let mut node = 0;
+        for i in 0..len {
+            if self.terminal[node] {
+                return true;
+            }
+            let bit = (bits >> (127 - i) & 1) as usize;
+            node = self.children[node][bit];
+            if node == 0 {
+                return false;
+            }
+        }
+        self.terminal[node]
+    }
+}
+
+fn max_prefix(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Zeroes all address bits past the first `prefix` ones.
+pub fn mask(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - prefix.min(32) as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        },
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - prefix.min(128) as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        },
+    }
+}
+
+/// Header a trusted proxy passes the visitor address in.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ForwardedHeader {
+    /// `X-Real-IP: 192.0.2.1`
+    XRealIp,
+    /// `X-Forwarded-For: 192.0.2.1, 10.0.0.1`
+    XForwardedFor,
+    /// RFC 7239 `Forwarded: for=192.0.2.1;proto=https, for="[2001:db8::1]:4711"`
+    Forwarded,
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "x-real-ip" => Ok(ForwardedHeader::XRealIp),
+            "x-forwarded-for" => Ok(ForwardedHeader::XForwardedFor),
+            "forwarded" => Ok(ForwardedHeader::Forwarded),
+            _ => Err(format!("unknown header {s}")),
+        }
+    }
+}
+
+impl ForwardedHeader {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ForwardedHeader::XRealIp => "X-Real-IP",
+            ForwardedHeader::XForwardedFor => "X-Forwarded-For",
+            ForwardedHeader::Forwarded => "Forwarded",
+        }
+    }
+}
+
+/// Finds the visitor address behind a chain of proxies. Forwarded headers are
+/// only believed if `peer` is a trusted proxy. Address lists are walked from
+/// the right, skipping trusted proxies, since anything left of the first 
+/// untrusted hop may have been made up by the client. Returns `peer` if the
+/// header is missing or unparsable.
+pub fn client_ip(peer: IpAddr, header: ForwardedHeader, value: Option<&str>, trusted: &PrefixSet) -> IpAddr {
+    if !trusted.contains(peer) {
+        return peer;
+    }
+    let Some(value) = value else {
+        return peer;
+    };
+
+    let hops = match header {
+        ForwardedHeader::XRealIp => vec![value.trim().parse::<IpAddr>().ok()],
+        ForwardedHeader::XForwardedFor => value.split(',').map(|hop| hop.trim().parse::<IpAddr>().ok()).collect(),
+        ForwardedHeader::Forwarded => value.split(',').map(forwarded_for).collect(),
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        let Some(hop) = hop else {
+            // An unknown or obfuscated hop; we can't see past it
+            break;
+        };
+        client = hop;
+        if !trusted.contains(hop) {
+            break;
+        }
+    }
+
+    client.to_canonical()
+}
+
+/// Extracts the `for=` address of one `Forwarded` element, e.g. 
+/// `for="[2001:db8::1]:4711";proto=https`.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    let node = element.split(';')
+                      .filter_map(|pair| pair.trim().split_once('='))
+                      .find(|(name, _)| name.eq_ignore_ascii_case("for"))?
+                      .1
+                      .trim_matches('"');
+
+    if let Some(v6) = node.strip_prefix('[') {
+        return v6.split_once(']')?.0.parse().ok();
+    }
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    let addr = node.split_once(':').map(|(addr, _port)| addr).unwrap_or(node);
+    addr.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(nets: &[&str]) -> PrefixSet {
+        nets.iter().map(|net| net.parse::<IpNet>().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_ipv4_ranges() {
+        let nets = set(&["10.0.0.0/8", "192.168.1.1", "172.16.0.0/12"]);
+        assert!(nets.contains(ip("10.255.0.1")));
+        assert!(nets.contains(ip("192.168.1.1")));
+        assert!(nets.contains(ip("172.31.255.255")));
+        assert!(!nets.contains(ip("192.168.1.2")));
+        assert!(!nets.contains(ip("172.32.0.0")));
+        assert!(!nets.contains(ip("11.0.0.0")));
+    }
+
+    #[test]
+    fn matches_ipv6_ranges() {
+        let nets = set(&["2001:db8::/32", "::1"]);
+        assert!(nets.contains(ip("2001:db8:ffff::1")));
+        assert!(nets.contains(ip("::1")));
+        assert!(!nets.contains(ip("2001:db9::1")));
+        assert!(!nets.contains(ip("::2")));
+    }
+
+    #[test]
+    fn keeps_address_families_apart() {
+        let nets = set(&["10.0.0.0/8"]);
+        assert!(!nets.contains(ip("a00::1")));
+
+        let nets = set(&["::/0"]);
+        assert!(nets.contains(ip("2001:db8::1")));
+        assert!(!nets.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn matches_v4_mapped_addresses_as_ipv4() {
+        let nets = set(&["10.0.0.0/8"]);
+        assert!(nets.contains(ip("::ffff:10.1.2.3")));
+        assert!(!nets.contains(ip("::ffff:11.1.2.3")));
+
+        let nets = set(&["::ffff:192.0.2.1"]);
+        assert!(nets.contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn handles_overlapping_and_catch_all_ranges() {
+        let nets = set(&["10.1.0.0/16", "10.0.0.0/8"]);
+        assert!(nets.contains(ip("10.1.2.3")));
+        assert!(nets.contains(ip("10.2.2.3")));
+
+        let nets = set(&["0.0.0.0/0"]);
+        assert!(nets.contains(ip("203.0.113.7")));
+
+        assert!(!PrefixSet::default().contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn parses_and_masks_ranges() {
+        assert_eq!("10.1.2.3/8".parse::<IpNet>().unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!("2001:db8::1/32".parse::<IpNet>().unwrap().to_string(), "2001:db8::/32");
+        assert_eq!("192.0.2.1".parse::<IpNet>().unwrap().to_string(), "192.0.2.1/32");
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+        assert_eq!(mask(ip("2001:db8:1:2:3:4:5:6"), 64), ip("2001:db8:1:2::"));
+    }
+
+    fn resolve(peer: &str, header: ForwardedHeader, value: Option<&str>) -> IpAddr {
+        client_ip(ip(peer), header, value, &set(&["127.0.0.0/8", "::1", "10.0.0.0/8"]))
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        assert_eq!(resolve("203.0.113.9", ForwardedHeader::XRealIp, Some("198.51.100.1")), ip("203.0.113.9"));
+        assert_eq!(resolve("203.0.113.9", ForwardedHeader::XForwardedFor, Some("198.51.100.1")), ip("203.0.113.9"));
+        assert_eq!(resolve("203.0.113.9", ForwardedHeader::Forwarded, Some("for=198.51.100.1")), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn falls_back_to_the_peer() {
+        assert_eq!(resolve("127.0.0.1", ForwardedHeader::XRealIp, None), ip("127.0.0.1"));
+        assert_eq!(resolve("127.0.0.1", ForwardedHeader::XRealIp, Some("garbage")), ip("127.0.0.1"));
+        assert_eq!(resolve("127.0.0.1", ForwardedHeader::XRealIp, Some(" 198.51.100.1 ")), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn skips_spoofed_leftmost_hops() {
+        // The client sent `X-Forwarded-For: 1.1.1.1`; the proxy appended its view of the client
+        let value = Some("1.1.1.1, 198.51.100.1");
+        assert_eq!(resolve("127.0.0.1", ForwardedHeader::XForwardedFor, value), ip("198.51.100.1"));
+
+        // Trusted proxies on the way are skipped
+        let value = Some("1.1.1.1, 198.51.100.1, 10.0.0.2, 10.0.0.3");
+        assert_eq!(resolve("127.0.0.1", ForwardedHeader::XForwardedFor, value), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn stops_at_unparsable_hops() {
+        let value = Some("198.51.100.1, unknown, 10.0.0.2");
+        assert_eq!(resolve("127.0.0.1", ForwardedHeader::XForwardedFor, value), ip("10.0.0.2"));
+
+        let value = Some("for=198.51.100.1, for=_hidden, for=10.0.0.2");
+        assert_eq!(resolve("127.0.0.1", ForwardedHeader::Forwarded, value), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn reads_rfc7239_forwarded_elements() {
+        let value = Some(r#"for=1.1.1.1, for="[2001:db8::1]:4711";proto=https, For=10.0.0.2:8080"#);
+        assert_eq!(resolve("::1", ForwardedHeader::Forwarded, value), ip("2001:db8::1"));
+
+        let value = Some("proto=https;for=198.51.100.1:443;by=10.0.0.1");
+        assert_eq!(resolve("127.0.0.1", ForwardedHeader::Forwarded, value), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn canonicalizes_v4_mapped_clients() {
+        assert_eq!(resolve("::ffff:127.0.0.1", ForwardedHeader::XRealIp, Some("::ffff:198.51.100.1")), ip("198.51.100.1"));
+    }
+}
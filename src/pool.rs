@@ -0,0 +1,69 @@
+use std::{net::TcpStream, panic::{AssertUnwindSafe, catch_unwind}, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}, mpsc::{self, Receiver, SyncSender, TrySendError}}, thread};
+
+use crate::util::lock;
+
+/// A fixed set of worker threads handling connections. At most
+/// `max_connections` connections are queued or being handled at once.
+pub struct Pool {
+    sender: SyncSender<TcpStream>,
+    active: Arc<AtomicUsize>,
+    max_connections: usize,
+}
+
+impl Pool {
+    pub fn new<F>(workers: usize, max_connections: usize, handler: F) -> Self
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        let max_connections = max_connections.max(1);
+        let (sender, receiver) = mpsc::sync_channel(max_connections);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let active = Arc::new(AtomicUsize::new(0));
+        let handler = Arc::new(handler);
+
+        for id in 0..workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let active = Arc::clone(&active);
+            let handler = Arc::clone(&handler);
+
+            let spawned = thread::Builder::new()
+                .name(format!("worker-{id}"))
+                .spawn(move || worker(&receiver, &active, handler.as_ref()));
+            if let Err(e) = spawned {
+                error!("Unable to spawn worker thread: {e}");
+            }
+        }
+
+        Pool { sender, active, max_connections }
+    }
+
+    /// Queues a connection for the workers. Gives the stream back if the
+    /// connection limit is reached.
+    pub fn execute(&self, stream: TcpStream) -> Result<(), TcpStream> {
+        if self.active.fetch_add(1, Ordering::SeqCst) >= self.max_connections {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            return Err(stream);
+        }
+
+        self.sender.try_send(stream).map_err(|e| {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            match e {
+                TrySendError::Full(stream) | TrySendError::Disconnected(stream) => stream,
+            }
+        })
+    }
+}
+
+fn worker(receiver: &Mutex<Receiver<TcpStream>>, active: &AtomicUsize, handler: &(dyn Fn(TcpStream) + Send + Sync)) {
+    loop {
+        let stream = lock(receiver).recv();
+        let Ok(stream) = stream else {
+            return;
+        };
+
+        if catch_unwind(AssertUnwindSafe(|| handler(stream))).is_err() {
+            error!("Worker panicked while handling a connection");
+        }
+        active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
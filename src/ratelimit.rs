@@ -0,0 +1,64 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::{Duration, Instant}};
+
+use crate::util::lock;
+
+/// Requests a client may make: `per_minute` on average, and up to `burst` at
+/// once after being idle.
+#[derive(Clone, Copy)]
+pub struct Limit {
+    pub per_minute: u32,
+    pub burst:      u32,
+}
+
+/// Token bucket of one client. Holds up to `burst` tokens, refilled at
+/// `per_minute`; each request takes one.
+struct Bucket {
+    tokens:  f64,
+    updated: Instant,
+}
+
+/// Per-IP token bucket rate limiter.
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    rate:    f64,
+    burst:   f64,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Limit) -> Self {
+        RateLimiter {
+            buckets: Mutex::new(HashMap::new()),
+            rate: limit.per_minute.max(1) as f64 / 60.0,
+            burst: limit.burst.max(1) as f64,
+        }
+    }
+
+    /// Takes a token for `ip`. Returns how long to wait for the next one if
+    /// the bucket is empty.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = lock(&self.buckets);
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: self.burst, updated: now });
+
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Forgets clients whose bucket has filled up again; they'd start with a
+    /// full bucket anyway.
+    pub fn clear_idle(&self) {
+        let now = Instant::now();
+        lock(&self.buckets).retain(|_, bucket| self.refilled(bucket, now) < self.burst);
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
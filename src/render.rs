@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+
+use crate::util::base64;
+
+/// Splits a counter value into `width` digit slots, most significant first.
+/// Slots past the leading digit are `None`, the same way `/get` shows them
+/// with the `empty` image.
+pub fn digits(count: usize, width: usize) -> Vec<Option<u8>> {
+    let mut slots = Vec::new();
+    let mut rest = count;
+    while rest > 0 {
+        slots.push(Some((rest % 10) as u8));
+        rest /= 10;
+    }
+    while slots.len() < width {
+        slots.push(None);
+    }
+
+    slots.reverse();
+    slots
+}
+
+/// Reads width and height from a PNG, GIF or JPEG header.
+pub fn image_size(image: &[u8]) -> Option<(u32, u32)> {
+    if image.starts_with(b"\x89PNG\r\n\x1a\n") && image.len() >= 24 {
+        let width = u32::from_be_bytes(image[16..20].try_into().ok()?);
+        let height = u32::from_be_bytes(image[20..24].try_into().ok()?);
+        return Some((width, height));
+    }
+
+    if image.starts_with(b"GIF8") && image.len() >= 10 {
+        let width = u16::from_le_bytes([image[6], image[7]]);
+        let height = u16::from_le_bytes([image[8], image[9]]);
+        return Some((width.into(), height.into()));
+    }
+
+    if image.starts_with(&[0xFF, 0xD8]) {
+        let mut pos = 2;
+        while pos + 4 <= image.len() {
+            if image[pos] != 0xFF {
+                return None;
+            }
+            let marker = image[pos + 1];
+            let length = u16::from_be_bytes([image[pos + 2], image[pos + 3]]) as usize;
+
+            // Start-of-frame markers; C4, C8 and CC are something else
+            if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+                let frame = image.get(pos + 5..pos + 9)?;
+                let height = u16::from_be_bytes([frame[0], frame[1]]);
+                let width = u16::from_be_bytes([frame[2], frame[3]]);
+                return Some((width.into(), height.into()));
+            }
+
+            pos += 2 + length;
+        }
+    }
+
+    None
+}
+
+/// Lays digit images out in a row as a single SVG, embedding each one as a
+/// `data:` URI.
+pub fn compose_svg(images: &[&[u8]], content_type: &str) -> Option<String> {
+    let mut body = String::new();
+    let mut x = 0;
+    let mut height = 0;
+
+    for image in images {
+        let (w, h) = image_size(image)?;
+        body.push_str(&format!(
+            "<image x=\"{x}\" y=\"0\" width=\"{w}\" height=\"{h}\" href=\"data:{content_type};base64,{}\"/>",
+            base64(image)
+        ));
+        x += w;
+        height = height.max(h);
+    }
+
+    Some(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{x}\" height=\"{height}\" viewBox=\"0 0 {x} {height}\">{body}</svg>"
+    ))
+}
+
+/// How digits are drawn: from image files or by the built-in SVG renderer.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Renderer {
+    Images,
+    Svg,
+}
+
+/// Look of digits drawn by the built-in SVG renderer.
+#[derive(Clone)]
+pub struct SvgStyle {
+    pub font_family:  String,
+    pub font_size:    u32,
+    pub fg_color:     String,
+    pub bg_color:     String,
+    pub border:       u32,
+    pub border_color: String,
+    pub padding:      u32,
+}
+
+impl Default for SvgStyle {
+    fn default() -> Self {
+        SvgStyle {
+            font_family: "monospace".to_owned(),
+            font_size: 20,
+            fg_color: "#00ff00".to_owned(),
+            bg_color: "#000000".to_owned(),
+            border: 1,
+            border_color: "#808080".to_owned(),
+            padding: 2,
+        }
+    }
+}
+
+impl SvgStyle {
+    /// Overrides style settings with the ones passed in a query string.
+    /// Unparsable values are ignored.
+    pub fn apply_args(&mut self, args: &HashMap<String, String>) {
+        if let Some(font) = args.get("font") {
+            self.font_family = font.to_string();
+        }
+        if let Some(size) = args.get("size").and_then(|s| s.parse::<u32>().ok()) {
+            self.font_size = size.min(MAX_FONT_SIZE);
+        }
+        if let Some(color) = args.get("fg") {
+            self.fg_color = color_value(color);
+        }
+        if let Some(color) = args.get("bg") {
+            self.bg_color = color_value(color);
+        }
+        if let Some(border) = args.get("border").and_then(|s| s.parse::<u32>().ok()) {
+            self.border = border.min(MAX_FONT_SIZE);
+        }
+        if let Some(color) = args.get("border_color") {
+            self.border_color = color_value(color);
+        }
+        if let Some(padding) = args.get("padding").and_then(|s| s.parse::<u32>().ok()) {
+            self.padding = padding.min(MAX_FONT_SIZE);
+        }
+    }
+}
+
+pub const MAX_FONT_SIZE: u32 = 500;
+
+/// Draws digit slots as text. Empty slots are drawn as blank cells of the 
+/// same width, so the image size only depends on the number of slots.
+pub fn draw_svg(slots: &[Option<u8>], style: &SvgStyle) -> String {
+    // Monospace digits are roughly 0.6em wide
+    let cell = style.font_size * 3 / 5 + 2 * style.padding;
+    let width = cell * slots.len() as u32 + 2 * style.border;
+    let height = style.font_size + 2 * style.padding + 2 * style.border;
+
+    let mut cells = String::new();
+    for (i, slot) in slots.iter().enumerate() {
+        if let Some(digit) = slot {
+            let x = style.border + cell * i as u32 + cell / 2;
+            cells.push_str(&format!("<text x=\"{x}\" y=\"{}\">{digit}</text>", height / 2));
+        }
+    }
+
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">\
+<rect x=\"{half}\" y=\"{half}\" width=\"{iw}\" height=\"{ih}\" fill=\"{bg}\" stroke=\"{bc}\" stroke-width=\"{b}\"/>\
+<g fill=\"{fg}\" font-family=\"{font}\" font-size=\"{size}\" text-anchor=\"middle\" dominant-baseline=\"central\">{cells}</g></svg>",
+        half = style.border as f32 / 2.0,
+        iw = width as f32 - style.border as f32,
+        ih = height as f32 - style.border as f32,
+        bg = escape_xml(&style.bg_color),
+        bc = escape_xml(&style.border_color),
+        b = style.border,
+        fg = escape_xml(&style.fg_color),
+        font = escape_xml(&style.font_family),
+        size = style.font_size,
+    )
+}
+
+/// Accepts colors with or without the leading `#`, since it has to be 
+/// percent-encoded in a URL.
+fn color_value(color: &str) -> String {
+    let is_hex = [3, 4, 6, 8].contains(&color.len()) && color.chars().all(|c| c.is_ascii_hexdigit());
+    if is_hex {
+        format!("#{color}")
+    } else {
+        color.to_owned()
+    }
+}
+
+fn escape_xml(value: &str) -> String {
+    value.replace('&', "&amp;")
+         .replace('<', "&lt;")
+         .replace('>', "&gt;")
+         .replace('"', "&quot;")
+         .replace('\'', "&apos;")
+}
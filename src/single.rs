@@ -46,8 +46,11 @@ impl SingleInstance {
 
         let maybe_sock = match socket::bind(sock.as_raw_fd(), &addr) {
             Ok(()) => Some(sock),
-            Err(nix::errno::Errno::EADDRINUSE) => None,
-            Err(e) => return Err(e.into()),
+            Err(nix::errno::Errno::EADDRINUSE) => {
+                debug!("Instance socket {name} is held by another process");
+                None
+            },
+            Err(e) => return Err(e),
         };
 
         Ok(Self { maybe_sock })
@@ -0,0 +1,137 @@
+use std::{fs::File, io::{ErrorKind, Read, Write}, path::Path, str::from_utf8, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}};
+
+use crate::util::lock;
+
+/// A file written from snapshots taken under a lock that shouldn't be held
+/// during disk I/O. Snapshots are numbered as they're taken, so one that
+/// reaches the disk late never replaces a newer one.
+#[derive(Default)]
+pub struct Slot {
+    /// Number of the newest snapshot written; held while writing.
+    written: Mutex<u64>,
+    taken:   AtomicU64,
+    /// Whether the last write failed, so the data is saved again even if it
+    /// doesn't change.
+    failed:  AtomicBool,
+}
+
+impl Slot {
+    pub fn snapshot(self: &Arc<Self>, path: String, bytes: Vec<u8>) -> Snapshot {
+        let number = self.taken.fetch_add(1, Ordering::SeqCst) + 1;
+        Snapshot { slot: Arc::clone(self), number, path, bytes }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+}
+
+/// Contents of a file at one point in time, waiting to be written.
+pub struct Snapshot {
+    slot:   Arc<Slot>,
+    number: u64,
+    path:   String,
+    bytes:  Vec<u8>,
+}
+
+impl Snapshot {
+    /// Writes the contents with `write_atomic`, unless a newer snapshot of
+    /// the same file has been written already.
+    fn write(&self) -> std::io::Result<()> {
+        let mut written = lock(&self.slot.written);
+        if *written > self.number {
+            return Ok(());
+        }
+        let result = write_atomic(&self.path, &self.bytes);
+        *written = self.number;
+        self.slot.failed.store(result.is_err(), Ordering::SeqCst);
+        result
+    }
+}
+
+/// Writes snapshots, reporting the ones that fail.
+pub fn write_snapshots(snapshots: impl IntoIterator<Item = Snapshot>) {
+    for snapshot in snapshots {
+        if let Err(e) = snapshot.write() {
+            error!("Error writing {}! {e}", snapshot.path);
+        }
+    }
+}
+
+/// Replaces a file so that a crash leaves either the old or the new contents
+/// on disk, never a truncated mix. The previous version is kept as
+/// `<path>.bak` for `load_number` to fall back to.
+pub fn write_atomic(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp = format!("{path}.tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    match std::fs::rename(path, backup_path(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {},
+    }
+    std::fs::rename(&tmp, path)?;
+
+    // Make the renames themselves durable
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Reads a counter value, recovering it from the backup copy if the file is
+/// missing or corrupt. A missing file without a backup means a fresh counter.
+/// Returns an error if neither copy can be read, so a damaged counter is
+/// never silently reset to zero.
+pub fn load_number(path: &str) -> Result<usize, String> {
+    let backup = backup_path(path);
+    match read_number(path) {
+        Ok(Some(v)) => return Ok(v),
+        Ok(None) => {
+            if !Path::new(&backup).exists() {
+                return Ok(0);
+            }
+            warn!("Counter file {path} is missing; recovering from {backup}");
+        },
+        Err(e) => warn!("{e}; recovering from {backup}"),
+    }
+
+    match read_number(&backup) {
+        Ok(Some(v)) => {
+            warn!("Recovered value {v} from {backup}");
+            Ok(v)
+        },
+        Ok(None) => Err(format!("Counter file {path} is corrupt and there's no backup")),
+        Err(e) => Err(format!("Counter file {path} and its backup are corrupt: {e}")),
+    }
+}
+
+fn backup_path(path: &str) -> String {
+    format!("{path}.bak")
+}
+
+/// Reads a number from a file. Returns `Ok(None)` if there's no such file.
+fn read_number(filepath: &str) -> Result<Option<usize>, String> {
+    let mut file = match File::open(filepath) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Unable to open file {filepath}: {e}")),
+        Ok(f) => f,
+    };
+
+    let mut buf = Vec::new();
+    if let Err(e) = file.read_to_end(&mut buf) {
+        return Err(format!("Unable to read file {filepath}: {e}"));
+    }
+
+    let Ok(snum) = from_utf8(&buf) else {
+        return Err(format!("Unable to parse file {filepath} to UTF-8"));
+    };
+
+    match snum.trim().parse::<usize>() {
+        Err(_) => Err(format!("Unable to parse value from {filepath}")),
+        Ok(v) => Ok(Some(v)),
+    }
+}
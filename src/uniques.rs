@@ -0,0 +1,190 @@
+use std::{collections::{HashMap, VecDeque}, hash::{BuildHasher, RandomState}, io::ErrorKind, net::IpAddr, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+/// How a counted visitor is remembered: by IP, or by a salted hash of IP 
+/// and user-agent that can't be turned back into either.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Visitor {
+    Ip(IpAddr),
+    Hashed(u64),
+}
+
+/// Secret keys for visitor hashes. The key is replaced every `rotation`; the
+/// previous one is kept so visitors counted just before a rotation are still
+/// recognized.
+struct Salt {
+    current:  RandomState,
+    previous: Option<RandomState>,
+    rotated:  Instant,
+    rotation: Duration,
+}
+
+impl Salt {
+    fn new(rotation: Duration) -> Self {
+        Salt { current: RandomState::new(), previous: None, rotated: Instant::now(), rotation }
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() >= self.rotation {
+            debug!("Rotating uniques salt");
+            self.previous = Some(std::mem::replace(&mut self.current, RandomState::new()));
+            self.rotated = Instant::now();
+        }
+    }
+}
+
+/// Visitors already counted, with the time of their counted visit.
+///
+/// The table holds at most `capacity` visitors. When it's full, the visitor 
+/// counted longest ago is forgotten to make room, so under a flood of new 
+/// visitors some returning ones are counted again before `timeout` passes.
+pub struct Uniques {
+    seen:     HashMap<Visitor, Instant>,
+    /// Visitors in the order they were counted, oldest first.
+    order:    VecDeque<(Visitor, Instant)>,
+    capacity: usize,
+    salt:     Option<Salt>,
+}
+
+impl Uniques {
+    /// Creates an empty table. With `hash_rotation` set, visitors are stored 
+    /// only as keyed hashes, and the key changes every `hash_rotation`.
+    pub fn new(capacity: usize, hash_rotation: Option<Duration>) -> Self {
+        Uniques { 
+            seen: HashMap::new(), 
+            order: VecDeque::new(), 
+            capacity: capacity.max(1), 
+            salt: hash_rotation.map(Salt::new) 
+        }
+    }
+
+    /// Loads a table saved by `save`, dropping entries older than `timeout`.
+    /// A missing or unreadable file gives an empty table.
+    pub fn load(path: &str, timeout: Duration, capacity: usize) -> Self {
+        let mut uniques = Uniques::new(capacity, None);
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return uniques,
+            Err(e) => {
+                warn!("Unable to read uniques from {path}: {e}; starting with an empty list");
+                return uniques;
+            },
+        };
+
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let mut entries = Vec::new();
+        for line in contents.lines() {
+            // <ip> <unix timestamp>
+            let entry = line.split_once(' ').and_then(|(ip, secs)| {
+                Some((ip.parse::<IpAddr>().ok()?, secs.parse::<u64>().ok()?))
+            });
+            let Some((ip, secs)) = entry else {
+                warn!("Skipping malformed line in {path}: {line}");
+                continue;
+            };
+
+            let age = wall_now.duration_since(UNIX_EPOCH + Duration::from_secs(secs)).unwrap_or_default();
+            if age >= timeout {
+                continue;
+            }
+            if let Some(timestamp) = now.checked_sub(age) {
+                entries.push((Visitor::Ip(ip), timestamp));
+            }
+        }
+
+        entries.sort_by_key(|(_, timestamp)| *timestamp);
+        for (visitor, timestamp) in entries {
+            uniques.insert_visitor(visitor, timestamp);
+        }
+
+        debug!("Loaded {} uniques from {path}", uniques.seen.len());
+        uniques
+    }
+
+    /// Encodes the table for `load`, with wall-clock timestamps so it survives
+    /// restarts. Hashed visitors are left out, since the salt exists only in 
+    /// memory.
+    pub fn encode(&self) -> Vec<u8> {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+
+        let mut contents = String::new();
+        for (visitor, timestamp) in self.seen.iter() {
+            let Visitor::Ip(ip) = visitor else {
+                continue;
+            };
+            let wall = wall_now - now.duration_since(*timestamp);
+            let secs = wall.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            contents.push_str(&format!("{ip} {secs}\n"));
+        }
+
+        contents.into_bytes()
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// Forgets every visitor, so everyone is counted again.
+    pub fn clear(&mut self) {
+        self.seen.clear();
+        self.order.clear();
+    }
+
+    pub fn contains(&mut self, ip: IpAddr, useragent: Option<&str>) -> bool {
+        let Some(salt) = &mut self.salt else {
+            return self.seen.contains_key(&Visitor::Ip(ip));
+        };
+        salt.rotate_if_due();
+
+        let current = Visitor::Hashed(salt.current.hash_one((ip, useragent)));
+        let previous = salt.previous.as_ref().map(|p| Visitor::Hashed(p.hash_one((ip, useragent))));
+        self.seen.contains_key(&current) || previous.is_some_and(|p| self.seen.contains_key(&p))
+    }
+
+    pub fn insert(&mut self, ip: IpAddr, useragent: Option<&str>) {
+        let visitor = match &mut self.salt {
+            Some(salt) => {
+                salt.rotate_if_due();
+                Visitor::Hashed(salt.current.hash_one((ip, useragent)))
+            },
+            None => Visitor::Ip(ip),
+        };
+        self.insert_visitor(visitor, Instant::now());
+    }
+
+    fn insert_visitor(&mut self, visitor: Visitor, timestamp: Instant) {
+        while self.seen.len() >= self.capacity {
+            let Some((oldest, counted)) = self.order.pop_front() else {
+                break;
+            };
+            // Skip queue entries whose visitor has already been removed
+            if self.seen.get(&oldest) == Some(&counted) {
+                debug!("Uniques list is full; forgetting the oldest visitor");
+                self.seen.remove(&oldest);
+            }
+        }
+
+        self.seen.insert(visitor, timestamp);
+        self.order.push_back((visitor, timestamp));
+    }
+
+    /// Forgets visitors counted more than `timeout` ago. `order` is sorted by
+    /// time, so this only looks at the entries being removed.
+    pub fn clear_timedout(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        while let Some((visitor, counted)) = self.order.front() {
+            if now.saturating_duration_since(*counted) < timeout {
+                break;
+            }
+
+            if self.seen.get(visitor) == Some(counted) {
+                if let Visitor::Ip(ip) = visitor {
+                    debug!("Removed from uniques list: {ip}");
+                }
+                self.seen.remove(visitor);
+            }
+            self.order.pop_front();
+        }
+    }
+}
@@ -1,17 +1,87 @@
+use std::{fmt, str::FromStr, sync::{Mutex, MutexGuard, PoisonError, atomic::{AtomicU8, Ordering}}, time::{SystemTime, UNIX_EPOCH}};
+
 use nix::unistd::Pid;
 
-#[cfg(debug_assertions)]
+/// Log levels, from most to least severe. Messages above the level set with
+/// `set_log_level` are dropped.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn label(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("unknown log level {s}")),
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_log_level(level: Level) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+fn log_enabled(level: Level) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Writes a message to stderr if its level is enabled. Use the `error!`,
+/// `warn!`, `info!`, `debug!` and `trace!` macros instead of calling this.
+pub fn log(level: Level, args: fmt::Arguments) {
+    if log_enabled(level) {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        eprintln!("{} {:<5} {args}", iso_datetime(secs), level.label());
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg: tt)+) => ($crate::util::log($crate::util::Level::Error, format_args!($($arg)+)));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg: tt)+) => ($crate::util::log($crate::util::Level::Warn, format_args!($($arg)+)));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg: tt)+) => ($crate::util::log($crate::util::Level::Info, format_args!($($arg)+)));
+}
+
 #[macro_export]
-macro_rules! debugprint {
-    ($x: expr) => (eprintln!("{}", $x));
-    ($x: expr, $($y: expr)+) => (eprint!("{} ", $x); debugprint!($($y),+));
+macro_rules! debug {
+    ($($arg: tt)+) => ($crate::util::log($crate::util::Level::Debug, format_args!($($arg)+)));
 }
 
-#[cfg(not(debug_assertions))]
 #[macro_export]
-macro_rules! debugprint {
-    ($x: expr) => ();
-    ($x: expr, $($y: expr)+) => ();
+macro_rules! trace {
+    ($($arg: tt)+) => ($crate::util::log($crate::util::Level::Trace, format_args!($($arg)+)));
 }
 
 pub fn kill_old_counter() -> nix::Result<()> {
@@ -23,12 +93,12 @@ pub fn kill_old_counter() -> nix::Result<()> {
                 return Ok(());
             },
             Err(e) => {
-                debugprint!("Error parsing PID: ", e);
+                warn!("Error parsing PID: {e}");
                 return Err(nix::errno::Errno::UnknownErrno);
             },
         }
     } else {
-        debugprint!("Error reading local PID. Seeking in tmp...");
+        debug!("Error reading local PID. Seeking in tmp...");
     }
 
     let mut tmpfilepath = std::env::temp_dir();
@@ -36,18 +106,15 @@ pub fn kill_old_counter() -> nix::Result<()> {
     let tmpfile = std::fs::read_to_string(tmpfilepath);
     if let Ok(pid) = tmpfile {
         match pid.parse::<i32>() {
-            Ok(pid) => {
-                kill(pid)?;
-                return Ok(());
-            },
+            Ok(pid) => kill(pid),
             Err(e) => {
-                debugprint!("Error parsing PID: ", e);
-                return Err(nix::errno::Errno::UnknownErrno);
+                warn!("Error parsing PID: {e}");
+                Err(nix::errno::Errno::UnknownErrno)
             },
         }
     } else {
-        debugprint!("Error reading temp PID. Giving up...");
-        return Err(nix::errno::Errno::EIO);
+        warn!("Error reading temp PID. Giving up...");
+        Err(nix::errno::Errno::EIO)
     }
 }
 
@@ -56,9 +123,147 @@ fn kill(pid: i32) -> Result<(), nix::errno::Errno> {
 }
 
 pub fn remove_pid_file() {
-    let rm = std::fs::remove_file(".counter.pid");
-    #[cfg(debug_assertions)]
-    if let Err(e) = rm {
-        debugprint!("Unable to remove PID file from current directory:", e);
+    if let Err(e) = std::fs::remove_file(".counter.pid") {
+        debug!("Unable to remove PID file from current directory: {e}");
     }
-}
\ No newline at end of file
+}
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Formats a timestamp as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[(days % 7) as usize], day, MONTHS[month as usize - 1], year,
+            secs % 86400 / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// Formats a timestamp for Common Log Format, e.g. `[10/Oct/2000:13:55:36 +0000]`
+/// without the brackets.
+pub fn clf_date(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            day, MONTHS[month as usize - 1], year, secs % 86400 / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// Formats a Unix timestamp as an ISO 8601 UTC date and time, e.g.
+/// `1994-11-06T08:49:37Z`.
+pub fn iso_datetime(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", secs % 86400 / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// Parses a `YYYY-MM-DD` date into the Unix timestamp of its midnight (UTC).
+pub fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.splitn(3, '-');
+    let year = parts.next()?.parse::<i64>().ok().filter(|y| (1970..=9999).contains(y))?;
+    let month = parts.next()?.parse::<u32>().ok().filter(|m| (1..=12).contains(m))?;
+    let day = parts.next()?.parse::<u32>().ok().filter(|d| (1..=31).contains(d))?;
+
+    let days = days_from_civil(year, month, day);
+    (civil_from_days(days) == (year, month, day)).then(|| days as u64 * 86400)
+}
+
+/// Converts a (year, month, day) date into days since the Unix epoch.
+/// See <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+/// Converts days since the Unix epoch into a (year, month, day) date.
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Locks a mutex, carrying on with the data even if another thread panicked 
+/// while holding it.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_between_days_and_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(days_from_civil(2100, 3, 1) - 1), (2100, 2, 28));
+
+        for days in (-800_000..800_000).step_by(97) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2024-01-01"), Some(1704067200));
+        assert_eq!(parse_date("2024-02-29"), Some(1709164800));
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("2024-04-31"), None);
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("1969-12-31"), None);
+        assert_eq!(parse_date("99999999999999-01-01"), None);
+        assert_eq!(parse_date("2024-01"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(iso_datetime(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso_datetime(1709210096), "2024-02-29T12:34:56Z");
+        assert_eq!(http_date(UNIX_EPOCH + std::time::Duration::from_secs(784111777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+}